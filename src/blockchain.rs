@@ -1,164 +1,3942 @@
-use rsa::{PublicKey, RsaPrivateKey, RsaPublicKey, PaddingScheme, Hash};
+use rsa::{PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey, PaddingScheme, Hash, BigUint};
+use rsa::pkcs1::ToRsaPublicKey;
 use rand::rngs::OsRng;
 use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
+use rayon::prelude::*;
+use log::info;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn generate_key_pair() -> (RsaPrivateKey, RsaPublicKey) {
+#[derive(Debug)]
+pub enum KeyGenError {
+  TooFewBits(usize),
+  Rsa(rsa::errors::Error),
+}
+
+impl fmt::Display for KeyGenError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      KeyGenError::TooFewBits(bits) => write!(f, "{} bit keys are too small, use at least {}", bits, MIN_KEY_BITS),
+      KeyGenError::Rsa(error) => write!(f, "failed to generate a key: {}", error),
+    }
+  }
+}
+
+impl std::error::Error for KeyGenError {}
+
+impl From<rsa::errors::Error> for KeyGenError {
+  fn from(error: rsa::errors::Error) -> Self {
+    KeyGenError::Rsa(error)
+  }
+}
+
+/// ```
+/// use shitcoin::blockchain::{generate_key_pair, Address, KeyGenError};
+///
+/// fn new_address() -> Result<Address, KeyGenError> {
+///   let (_private_key, public_key) = generate_key_pair()?;
+///   Ok(Address::from_public_key(&public_key))
+/// }
+///
+/// println!("{}", new_address().unwrap());
+/// ```
+pub fn generate_key_pair() -> Result<(RsaPrivateKey, RsaPublicKey), KeyGenError> {
+  generate_key_pair_with_bits(DEFAULT_KEY_BITS)
+}
+
+pub const DEFAULT_KEY_BITS: usize = 2048;
+
+// anything smaller can be factored
+pub const MIN_KEY_BITS: usize = 1024;
+
+pub fn generate_key_pair_with_bits(bits: usize) -> Result<(RsaPrivateKey, RsaPublicKey), KeyGenError> {
+  if bits < MIN_KEY_BITS {
+    return Err(KeyGenError::TooFewBits(bits));
+  }
   let mut rng = OsRng;
-  let bits = 2048;
-  let private_key = RsaPrivateKey::new(&mut rng, bits).expect("failed to generate a key");
+  let private_key = RsaPrivateKey::new(&mut rng, bits)?;
   let public_key = RsaPublicKey::from(&private_key);
-  (private_key, public_key)
+  Ok((private_key, public_key))
+}
+
+// the padding a signature was made with, verification has to use the same one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SignatureScheme {
+  #[default]
+  Pkcs1v15,
+  Pss,
+}
+
+impl SignatureScheme {
+  fn padding(self) -> PaddingScheme {
+    match self {
+      SignatureScheme::Pkcs1v15 => PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+      SignatureScheme::Pss => PaddingScheme::new_pss::<sha2_09::Sha256, _>(OsRng),
+    }
+  }
+}
+
+pub fn sign_message(message: impl AsRef<[u8]>, private_key: &RsaPrivateKey) -> Vec<u8>{
+  sign_message_with(message, private_key, SignatureScheme::Pkcs1v15)
+}
+
+pub fn sign_message_with(message: impl AsRef<[u8]>, private_key: &RsaPrivateKey, scheme: SignatureScheme) -> Vec<u8>{
+  let hash = sha256(message.as_ref());
+  private_key.sign(scheme.padding(), hash.as_bytes()).expect("failed to sign message")
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+  BadSignatureFormat,
+  SignatureMismatch,
+}
+
+impl fmt::Display for VerifyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      VerifyError::BadSignatureFormat => write!(f, "signature isn't the size of the key"),
+      VerifyError::SignatureMismatch => write!(f, "signature doesn't match the message"),
+    }
+  }
+}
+
+impl std::error::Error for VerifyError {}
+
+pub fn verify_message(message: impl AsRef<[u8]>, signature: &[u8], public_key: &RsaPublicKey) -> bool {
+  verify_message_detailed(message, signature, public_key).is_ok()
+}
+
+pub fn verify_message_detailed(message: impl AsRef<[u8]>, signature: &[u8], public_key: &RsaPublicKey) -> Result<(), VerifyError> {
+  verify_message_with(message, signature, public_key, SignatureScheme::Pkcs1v15)
+}
+
+pub fn verify_message_with(message: impl AsRef<[u8]>, signature: &[u8], public_key: &RsaPublicKey, scheme: SignatureScheme) -> Result<(), VerifyError> {
+  // signatures under either scheme are always exactly as long as the modulus
+  if signature.len() != public_key.size() {
+    return Err(VerifyError::BadSignatureFormat);
+  }
+  let hash = sha256(message.as_ref());
+  public_key.verify(scheme.padding(), hash.as_bytes(), signature).map_err(|_| VerifyError::SignatureMismatch)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct SHA256Hash([u8; 32]);
+
+impl SHA256Hash {
+  pub fn as_bytes(&self) -> &[u8; 32] {
+    &self.0
+  }
+
+  // cut to the first 8 hex digits for summaries, where all 64 would crowd out the rest of the line
+  pub fn short(&self) -> String {
+    self.to_string()[..8].to_string()
+  }
+}
+
+// compares in constant time, for checking a hash someone else handed us against one we worked out
+fn hashes_equal(a: &SHA256Hash, b: &SHA256Hash) -> bool {
+  a.0.ct_eq(&b.0).into()
+}
+
+impl From<[u8; 32]> for SHA256Hash {
+  fn from(bytes: [u8; 32]) -> Self {
+    SHA256Hash(bytes)
+  }
+}
+
+impl From<sha2::digest::Output<Sha256>> for SHA256Hash {
+  fn from(output: sha2::digest::Output<Sha256>) -> Self {
+    SHA256Hash(output.into())
+  }
+}
+
+pub fn sha256(data: &[u8]) -> SHA256Hash {
+  sha256_many(&[data])
+}
+
+// hashes the parts as if they were concatenated, without building the concatenation
+pub fn sha256_many(parts: &[&[u8]]) -> SHA256Hash {
+  let mut hasher = Sha256::new();
+  for part in parts {
+    hasher.update(part);
+  }
+  SHA256Hash::from(hasher.finalize())
+}
+
+// sha256 of sha256, which shrugs off length-extension attacks on the inner hash
+pub fn double_sha256(data: &[u8]) -> SHA256Hash {
+  sha256(sha256(data).as_bytes())
+}
+
+// every node on a network has to agree on this, so it's fixed when the crate is built
+pub const DOUBLE_SHA256_BLOCKS: bool = cfg!(feature = "double-sha256");
+
+fn block_hash(data: &[u8], double: bool) -> SHA256Hash {
+  if double {
+    double_sha256(data)
+  } else {
+    sha256(data)
+  }
+}
+
+impl fmt::Display for SHA256Hash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for byte in &self.0 {
+      write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for SHA256Hash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseHashError {
+  InvalidLength(usize),
+  InvalidHex,
+}
+
+impl fmt::Display for ParseHashError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ParseHashError::InvalidLength(length) => write!(f, "expected 64 hex characters, got {}", length),
+      ParseHashError::InvalidHex => write!(f, "hash contains non-hex characters"),
+    }
+  }
+}
+
+impl std::error::Error for ParseHashError {}
+
+impl FromStr for SHA256Hash {
+  type Err = ParseHashError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.len() != 64 {
+      return Err(ParseHashError::InvalidLength(s.len()));
+    }
+    // checked up front since from_str_radix would also accept a leading '+'
+    if !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+      return Err(ParseHashError::InvalidHex);
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| ParseHashError::InvalidHex)?;
+    }
+    Ok(SHA256Hash(bytes))
+  }
+}
+
+// hex in formats meant for people, raw bytes in binary ones
+impl Serialize for SHA256Hash {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    if !serializer.is_human_readable() {
+      return self.0.serialize(serializer);
+    }
+    serializer.serialize_str(&self.to_string())
+  }
+}
+
+impl<'de> Deserialize<'de> for SHA256Hash {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    if !deserializer.is_human_readable() {
+      return <[u8; 32]>::deserialize(deserializer).map(SHA256Hash);
+    }
+    let hex = String::deserialize(deserializer)?;
+    hex.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+// previous_hash of the first block in a chain
+pub const ZERO_HASH: SHA256Hash = SHA256Hash([0; 32]);
+
+#[cfg(test)]
+thread_local! {
+  // block and transaction hashes computed on this thread, so tests can see what caching saves
+  static HASH_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[inline(always)]
+fn count_hash() {
+  #[cfg(test)]
+  HASH_COUNT.with(|count| count.set(count.get() + 1));
+}
+
+fn mining_threads() -> usize {
+  std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1)
+}
+
+// seconds since the unix epoch
+pub(crate) fn unix_time() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock is before 1970").as_secs()
+}
+
+// number of leading zero bits a block hash needs
+pub const DEFAULT_DIFFICULTY: u32 = 32;
+
+// amounts are whole numbers of the smallest unit, this many of them make one coin
+pub const COIN: u64 = 100_000_000;
+
+// paid to the miner of each block
+pub const BLOCK_REWARD: u64 = 50 * COIN;
+
+// the reward halves every HALVING_INTERVAL blocks until it reaches zero
+pub const HALVING_INTERVAL: u64 = 210_000;
+
+// what the coinbase of the block at height may mint on top of its fees, with the default params
+pub fn block_reward(height: u64) -> u64 {
+  ChainParams::default().block_reward(height)
+}
+
+// an amount as a decimal number of coins, e.g. 150_000_000 is "1.5"
+pub fn format_amount(amount: u64) -> String {
+  let coins = amount / COIN;
+  let fraction = amount % COIN;
+  if fraction == 0 {
+    return coins.to_string();
+  }
+  let digits = format!("{:08}", fraction);
+  format!("{}.{}", coins, digits.trim_end_matches('0'))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseAmountError {
+  Invalid,
+  TooPrecise,
+  Overflow,
+}
+
+impl fmt::Display for ParseAmountError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ParseAmountError::Invalid => write!(f, "amount must be a decimal number of coins"),
+      ParseAmountError::TooPrecise => write!(f, "amount has more than 8 decimal places"),
+      ParseAmountError::Overflow => write!(f, "amount is too large"),
+    }
+  }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+// the inverse of format_amount, "1.5" is 150_000_000
+pub fn parse_amount(text: &str) -> Result<u64, ParseAmountError> {
+  let (coins, fraction) = text.split_once('.').unwrap_or((text, ""));
+  let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+  if coins.is_empty() || !all_digits(coins) || !all_digits(fraction) {
+    return Err(ParseAmountError::Invalid);
+  }
+  if fraction.len() > 8 {
+    return Err(ParseAmountError::TooPrecise);
+  }
+  let coins: u64 = coins.parse().map_err(|_| ParseAmountError::Overflow)?;
+  let fraction: u64 = format!("{:0<8}", fraction).parse().expect("eight ascii digits always parse");
+  coins.checked_mul(COIN).and_then(|amount| amount.checked_add(fraction)).ok_or(ParseAmountError::Overflow)
+}
+
+// how often blocks should be mined, in seconds
+pub const TARGET_BLOCK_SECONDS: u64 = 60;
+
+// difficulty is adjusted once every this many mined blocks
+pub const RETARGET_INTERVAL: usize = 10;
+
+// a block has to be newer than the median timestamp of this many blocks before it
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// and can't claim to be more than this far ahead of our clock
+pub const MAX_FUTURE_BLOCK_SECONDS: u64 = 2 * 60 * 60;
+
+// transactions a block may carry besides its coinbase, to keep blocks small enough to send around
+pub const MAX_TRANSACTIONS_PER_BLOCK: usize = 1000;
+
+fn default_initial_reward() -> u64 {
+  BLOCK_REWARD
+}
+
+fn default_halving_interval() -> u64 {
+  HALVING_INTERVAL
+}
+
+fn default_max_block_txs() -> usize {
+  MAX_TRANSACTIONS_PER_BLOCK
+}
+
+fn default_key_bits() -> usize {
+  DEFAULT_KEY_BITS
+}
+
+// what makes a block count
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsensusMode {
+  // meeting the difficulty target
+  #[default]
+  ProofOfWork,
+  // being mined by one of validators; there's no work, so the difficulty stays at zero
+  ProofOfAuthority {
+    #[serde(with = "public_keys_der")]
+    validators: Vec<RsaPublicKey>,
+  },
+}
+
+// the rules a chain is mined under; the defaults are the constants above. they go into the
+// genesis block, so chains with different params never share a block and can't be mixed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainParams {
+  // until the first retarget
+  #[serde(alias = "difficulty")]
+  pub initial_difficulty: u32,
+  pub target_block_seconds: u64,
+  // chain files from before these were configurable have them at their defaults
+  #[serde(default = "default_initial_reward")]
+  pub initial_reward: u64,
+  #[serde(default = "default_halving_interval")]
+  pub halving_interval: u64,
+  #[serde(default = "default_max_block_txs")]
+  pub max_block_txs: usize,
+  // every uid has to come from derive_uid for its sender
+  #[serde(default)]
+  pub derived_uids: bool,
+  #[serde(default)]
+  pub consensus: ConsensusMode,
+  // the size of keys generated for this network; it isn't a consensus rule, so it stays
+  // out of the genesis block
+  #[serde(default = "default_key_bits")]
+  pub key_bits: usize,
+}
+
+impl Default for ChainParams {
+  fn default() -> Self {
+    ChainParams::mainnet()
+  }
+}
+
+impl ChainParams {
+  pub fn mainnet() -> Self {
+    ChainParams {
+      initial_difficulty: DEFAULT_DIFFICULTY,
+      target_block_seconds: TARGET_BLOCK_SECONDS,
+      initial_reward: BLOCK_REWARD,
+      halving_interval: HALVING_INTERVAL,
+      max_block_txs: MAX_TRANSACTIONS_PER_BLOCK,
+      derived_uids: false,
+      consensus: ConsensusMode::ProofOfWork,
+      key_bits: DEFAULT_KEY_BITS,
+    }
+  }
+
+  // for development: blocks take no real work and keys are quick to generate
+  pub fn testnet() -> Self {
+    ChainParams {
+      initial_difficulty: 1,
+      target_block_seconds: 5,
+      derived_uids: true,
+      key_bits: MIN_KEY_BITS,
+      ..ChainParams::mainnet()
+    }
+  }
+
+  pub fn generate_key_pair(&self) -> Result<(RsaPrivateKey, RsaPublicKey), KeyGenError> {
+    generate_key_pair_with_bits(self.key_bits)
+  }
+
+  // what the coinbase of the block at height may mint on top of its fees
+  pub fn block_reward(&self, height: u64) -> u64 {
+    let halvings = height.checked_div(self.halving_interval).unwrap_or(0);
+    u32::try_from(halvings).ok().and_then(|halvings| self.initial_reward.checked_shr(halvings)).unwrap_or(0)
+  }
+
+  pub fn genesis_hash(&self) -> SHA256Hash {
+    Block::genesis(self).hash
+  }
+
+  // the genesis block isn't mined, so its nonce is free to carry the params
+  fn genesis_nonce(&self) -> u64 {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&self.initial_difficulty.to_be_bytes());
+    bytes.extend_from_slice(&self.target_block_seconds.to_be_bytes());
+    bytes.extend_from_slice(&self.initial_reward.to_be_bytes());
+    bytes.extend_from_slice(&self.halving_interval.to_be_bytes());
+    bytes.extend_from_slice(&(self.max_block_txs as u64).to_be_bytes());
+    // only when set, so chains from before the flag keep their genesis
+    if self.derived_uids {
+      bytes.push(0x01);
+    }
+    if let ConsensusMode::ProofOfAuthority { validators } = &self.consensus {
+      bytes.push(0x02);
+      for validator in validators {
+        bytes.extend_from_slice(&public_key_der(validator));
+      }
+    }
+    let hash = sha256(&bytes);
+    u64::from_be_bytes(hash.0[..8].try_into().expect("a hash is longer than 8 bytes"))
+  }
+}
+
+// a uid whose top half comes from the sender's address and bottom half from a counter the
+// sender keeps; two senders can only pick the same uid if their addresses start the same
+pub fn derive_uid(sender: &Address, counter: u32) -> u64 {
+  u64::from(sender.uid_prefix()) << 32 | u64::from(counter)
+}
+
+// the largest hash, read as a big-endian number, that has `bits` leading zero bits
+pub fn target_from_leading_zero_bits(bits: u32) -> SHA256Hash {
+  let mut target = [0xff; 32];
+  for (i, byte) in target.iter_mut().enumerate() {
+    let start = i as u32 * 8;
+    if bits >= start + 8 {
+      *byte = 0;
+    } else if bits > start {
+      *byte = 0xff >> (bits - start);
+    }
+  }
+  SHA256Hash(target)
+}
+
+// difficulty counts leading zero bits, so each one doubles the nonces a miner can expect to try
+pub fn expected_attempts(difficulty: u32) -> f64 {
+  2_f64.powi(difficulty as i32)
+}
+
+// how long a miner doing hashes_per_sec can expect to take at difficulty
+pub fn estimate_seconds(difficulty: u32, hashes_per_sec: f64) -> f64 {
+  expected_attempts(difficulty) / hashes_per_sec
+}
+
+// sends newly minted coins and "mines" the genesis block; nobody holds a private key for it
+pub fn sentinel_key() -> RsaPublicKey {
+  RsaPublicKey::new(BigUint::from(0_u8), BigUint::from(65537_u32)).expect("failed to create sentinel key")
+}
+
+// PKCS#1 DER encoding of a public key, which unlike its Debug output is stable
+pub fn public_key_der(key: &RsaPublicKey) -> Vec<u8> {
+  key.to_pkcs1_der().expect("failed to encode public key").as_der().to_vec()
+}
+
+// public keys are serialized as PKCS#1 DER so the encoding doesn't depend on rsa's internals
+mod public_key_der {
+  use rsa::RsaPublicKey;
+  use rsa::pkcs1::FromRsaPublicKey;
+  use serde::{Deserialize, Deserializer, Serializer};
+  use serde::de::Error as _;
+
+  pub fn serialize<S: Serializer>(key: &RsaPublicKey, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(&super::public_key_der(key))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RsaPublicKey, D::Error> {
+    let der = Vec::<u8>::deserialize(deserializer)?;
+    RsaPublicKey::from_pkcs1_der(&der).map_err(D::Error::custom)
+  }
+}
+
+// extra transaction outputs, each key encoded the same way as public_key_der
+mod outputs_der {
+  use rsa::RsaPublicKey;
+  use rsa::pkcs1::FromRsaPublicKey;
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+  use serde::de::Error as _;
+
+  pub fn serialize<S: Serializer>(outputs: &[(RsaPublicKey, u64)], serializer: S) -> Result<S::Ok, S::Error> {
+    let encoded: Vec<(Vec<u8>, u64)> = outputs.iter()
+      .map(|(key, amount)| (super::public_key_der(key), *amount))
+      .collect();
+    encoded.serialize(serializer)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(RsaPublicKey, u64)>, D::Error> {
+    let encoded = Vec::<(Vec<u8>, u64)>::deserialize(deserializer)?;
+    encoded.into_iter()
+      .map(|(der, amount)| RsaPublicKey::from_pkcs1_der(&der).map(|key| (key, amount)).map_err(D::Error::custom))
+      .collect()
+  }
+}
+
+// a list of keys, each encoded the same way as public_key_der
+mod public_keys_der {
+  use rsa::RsaPublicKey;
+  use rsa::pkcs1::FromRsaPublicKey;
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+  use serde::de::Error as _;
+
+  pub fn serialize<S: Serializer>(keys: &[RsaPublicKey], serializer: S) -> Result<S::Ok, S::Error> {
+    let encoded: Vec<Vec<u8>> = keys.iter().map(super::public_key_der).collect();
+    encoded.serialize(serializer)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<RsaPublicKey>, D::Error> {
+    let encoded = Vec::<Vec<u8>>::deserialize(deserializer)?;
+    encoded.iter().map(|der| RsaPublicKey::from_pkcs1_der(der).map_err(D::Error::custom)).collect()
+  }
+}
+
+// short identifier for a public key, the hash of its DER encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Address(SHA256Hash);
+
+impl Address {
+  pub fn from_public_key(key: &RsaPublicKey) -> Self {
+    Address(sha256(&public_key_der(key)))
+  }
+
+  fn uid_prefix(&self) -> u32 {
+    u32::from_be_bytes(self.0 .0[..4].try_into().expect("a hash is longer than 4 bytes"))
+  }
+
+  // the first 8 hex digits, enough to tell addresses apart by eye
+  pub fn short(&self) -> String {
+    self.to_string()[..8].to_string()
+  }
+}
+
+impl fmt::Display for Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl FromStr for Address {
+  type Err = ParseHashError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    s.parse().map(Address)
+  }
+}
+
+// a transaction's hash, worked out the first time it's needed; the fields it covers
+// are private and never change after construction, so it can't go stale
+#[derive(Debug, Clone, Default)]
+struct HashCache(OnceLock<SHA256Hash>);
+
+// the cache is derived from the other fields, so it never makes two transactions differ
+impl PartialEq for HashCache {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transaction {
+  #[serde(with = "public_key_der")]
+  sender: RsaPublicKey,
+  // older files spell it reciver
+  #[serde(alias = "reciver", with = "public_key_der")]
+  receiver: RsaPublicKey,
+  amount: u64,
+  // payments to anyone besides receiver, covered by the same signature
+  #[serde(default, with = "outputs_der")]
+  extra_outputs: Vec<(RsaPublicKey, u64)>,
+  // paid to whoever mines the transaction, on top of the outputs
+  #[serde(default)]
+  fee: u64,
+  uid: u64,
+  // the last height the transaction may be mined at, so an old signature can't be dug up later
+  #[serde(default)]
+  valid_until_height: Option<u64>,
+  // not signed over, a signature only verifies under the scheme that made it anyway
+  #[serde(default)]
+  scheme: SignatureScheme,
+  signature: Vec<u8>,
+  #[serde(skip)]
+  hash_cache: HashCache,
 }
 
-pub fn sign_message(message: String, private_key: &RsaPrivateKey) -> Vec<u8>{
-  let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
-  let mut hasher = Sha256::new();
-  hasher.update(message);
-  let hash = hasher.finalize();
-  let signature = private_key.sign(padding, &hash).expect("failed to sign message");
-  signature
-}
+impl Transaction {
+  pub fn new(sender: RsaPublicKey, sender_private_key: RsaPrivateKey, receiver: RsaPublicKey, amount: u64, uid: u64) -> Self {
+    Self::with_outputs(sender, sender_private_key, vec![(receiver, amount)], uid)
+  }
+
+  pub fn with_fee(sender: RsaPublicKey, sender_private_key: RsaPrivateKey, receiver: RsaPublicKey, amount: u64, fee: u64, uid: u64) -> Self {
+    Self::signed(sender, &sender_private_key, vec![(receiver, amount)], fee, uid, None, SignatureScheme::Pkcs1v15)
+  }
+
+  // pays every output from one signature, the first output becomes the receiver
+  pub fn with_outputs(sender: RsaPublicKey, sender_private_key: RsaPrivateKey, outputs: Vec<(RsaPublicKey, u64)>, uid: u64) -> Self {
+    Self::signed(sender, &sender_private_key, outputs, 0, uid, None, SignatureScheme::Pkcs1v15)
+  }
+
+  fn signed(sender: RsaPublicKey, sender_private_key: &RsaPrivateKey, outputs: Vec<(RsaPublicKey, u64)>, fee: u64, uid: u64, valid_until_height: Option<u64>, scheme: SignatureScheme) -> Self {
+    let mut outputs = outputs.into_iter();
+    let (receiver, amount) = outputs.next().expect("a transaction needs at least one output");
+    let mut transaction = Transaction { sender, receiver, amount, extra_outputs: outputs.collect(), fee, uid, valid_until_height, scheme, signature: Vec::new(), hash_cache: HashCache::default() };
+    transaction.signature = sign_message_with(transaction.signing_bytes(), sender_private_key, scheme);
+    transaction
+  }
+
+  pub fn sender(&self) -> &RsaPublicKey {
+    &self.sender
+  }
+
+  pub fn receiver(&self) -> &RsaPublicKey {
+    &self.receiver
+  }
+
+  pub fn amount(&self) -> u64 {
+    self.amount
+  }
+
+  // every (receiver, amount) the transaction pays, starting with the primary one
+  pub fn outputs(&self) -> impl Iterator<Item = (&RsaPublicKey, u64)> {
+    std::iter::once((&self.receiver, self.amount))
+      .chain(self.extra_outputs.iter().map(|(key, amount)| (key, *amount)))
+  }
+
+  // what the sender pays across all outputs
+  // saturates instead of overflowing, no balance can cover u64::MAX anyway
+  pub fn total_amount(&self) -> u64 {
+    self.outputs().fold(0, |total, (_, amount)| total.saturating_add(amount))
+  }
+
+  pub fn fee(&self) -> u64 {
+    self.fee
+  }
+
+  // what leaves the sender's balance, outputs plus fee
+  pub fn cost(&self) -> u64 {
+    self.total_amount().saturating_add(self.fee)
+  }
+
+  pub fn uid(&self) -> u64 {
+    self.uid
+  }
+
+  // whether the uid is one derive_uid could have made for the sender
+  pub fn uid_encodes_sender(&self) -> bool {
+    self.uid >> 32 == u64::from(Address::from_public_key(&self.sender).uid_prefix())
+  }
+
+  // mints new coins for a miner, so there's no sender to sign it
+  pub fn coinbase(miner: RsaPublicKey, amount: u64, uid: u64) -> Self {
+    Transaction { sender: sentinel_key(), receiver: miner, amount, extra_outputs: Vec::new(), fee: 0, uid, valid_until_height: None, scheme: SignatureScheme::default(), signature: Vec::new(), hash_cache: HashCache::default() }
+  }
+
+  pub fn is_coinbase(&self) -> bool {
+    self.sender == sentinel_key()
+  }
+
+  // every output moves something; a coinbase is exempt since the reward runs out eventually
+  pub fn has_valid_amounts(&self) -> bool {
+    self.is_coinbase() || self.outputs().all(|(_, amount)| amount > 0)
+  }
+
+  // coins sent to the sentinel key could never be spent again
+  pub fn pays_sentinel(&self) -> bool {
+    let sentinel = sentinel_key();
+    self.outputs().any(|(receiver, _)| *receiver == sentinel)
+  }
+
+  // the bytes covered by the signature; DER is self-delimiting so plain concatenation is unambiguous
+  pub fn signing_bytes(&self) -> Vec<u8> {
+    let mut bytes = public_key_der(&self.sender);
+    bytes.extend(public_key_der(&self.receiver));
+    bytes.extend(self.amount.to_be_bytes());
+    bytes.extend(self.fee.to_be_bytes());
+    bytes.extend(self.uid.to_be_bytes());
+    for (key, amount) in &self.extra_outputs {
+      bytes.extend(public_key_der(key));
+      bytes.extend(amount.to_be_bytes());
+    }
+    // left out when unset so transactions signed before expiry existed still verify;
+    // DER always starts with 0x30, so the tag can't be mistaken for another output
+    if let Some(height) = self.valid_until_height {
+      bytes.push(0x01);
+      bytes.extend(height.to_be_bytes());
+    }
+    bytes
+  }
+
+  pub fn valid_until_height(&self) -> Option<u64> {
+    self.valid_until_height
+  }
+
+  // whether the transaction may still be mined in a block at height
+  pub fn is_valid_at(&self, height: u64) -> bool {
+    self.valid_until_height.is_none_or(|last| height <= last)
+  }
+
+  pub fn verify(&self) -> bool {
+    if self.is_coinbase() {
+      return self.signature.is_empty();
+    }
+    verify_message_with(self.signing_bytes(), &self.signature, &self.sender, self.scheme).is_ok()
+  }
+
+  pub fn scheme(&self) -> SignatureScheme {
+    self.scheme
+  }
+
+  // compact binary encoding, for when JSON is too big
+  pub fn to_bytes(&self) -> Vec<u8> {
+    bincode::serialize(self).expect("failed to encode transaction")
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Transaction> {
+    bincode::deserialize(bytes)
+  }
+
+  // covers the signing bytes and the signature, so identical signed transactions hash equal
+  pub fn hash(&self) -> SHA256Hash {
+    *self.hash_cache.0.get_or_init(|| {
+      count_hash();
+      sha256_many(&[&self.signing_bytes(), &self.signature])
+    })
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+  MissingSender,
+  MissingReceiver,
+  MissingAmount,
+  MissingUid,
+  MissingSigningKey,
+}
+
+impl fmt::Display for BuildError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let missing = match self {
+      BuildError::MissingSender => "sender",
+      BuildError::MissingReceiver => "receiver",
+      BuildError::MissingAmount => "amount",
+      BuildError::MissingUid => "uid",
+      BuildError::MissingSigningKey => "signing key",
+    };
+    write!(f, "transaction has no {}", missing)
+  }
+}
+
+impl std::error::Error for BuildError {}
+
+// names every part of a transaction so the two public keys can't be swapped by accident,
+// e.g. TransactionBuilder::new().sender(a).receiver(b).amount(COIN).uid(1).sign_with(key).build()
+#[derive(Default)]
+pub struct TransactionBuilder {
+  sender: Option<RsaPublicKey>,
+  receiver: Option<RsaPublicKey>,
+  amount: Option<u64>,
+  fee: u64,
+  uid: Option<u64>,
+  valid_until_height: Option<u64>,
+  scheme: SignatureScheme,
+  private_key: Option<RsaPrivateKey>,
+}
+
+impl TransactionBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn sender(mut self, sender: RsaPublicKey) -> Self {
+    self.sender = Some(sender);
+    self
+  }
+
+  pub fn receiver(mut self, receiver: RsaPublicKey) -> Self {
+    self.receiver = Some(receiver);
+    self
+  }
+
+  pub fn amount(mut self, amount: u64) -> Self {
+    self.amount = Some(amount);
+    self
+  }
+
+  // optional, defaults to no fee
+  pub fn fee(mut self, fee: u64) -> Self {
+    self.fee = fee;
+    self
+  }
+
+  pub fn uid(mut self, uid: u64) -> Self {
+    self.uid = Some(uid);
+    self
+  }
+
+  // optional, defaults to PKCS#1 v1.5
+  pub fn scheme(mut self, scheme: SignatureScheme) -> Self {
+    self.scheme = scheme;
+    self
+  }
+
+  // optional, defaults to never expiring
+  pub fn valid_until_height(mut self, height: u64) -> Self {
+    self.valid_until_height = Some(height);
+    self
+  }
+
+  pub fn sign_with(mut self, private_key: RsaPrivateKey) -> Self {
+    self.private_key = Some(private_key);
+    self
+  }
+
+  pub fn build(self) -> Result<Transaction, BuildError> {
+    let sender = self.sender.ok_or(BuildError::MissingSender)?;
+    let receiver = self.receiver.ok_or(BuildError::MissingReceiver)?;
+    let amount = self.amount.ok_or(BuildError::MissingAmount)?;
+    let uid = self.uid.ok_or(BuildError::MissingUid)?;
+    let private_key = self.private_key.ok_or(BuildError::MissingSigningKey)?;
+    Ok(Transaction::signed(sender, &private_key, vec![(receiver, amount)], self.fee, uid, self.valid_until_height, self.scheme))
+  }
+}
+
+// e.g. "1a2b3c4d -> 5e6f7a8b 1.5, 9c0d1e2f 2 fee 0.1 (uid 7, verified)"
+impl fmt::Display for Transaction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.is_coinbase() {
+      write!(f, "coinbase ->")?;
+    } else {
+      write!(f, "{} ->", Address::from_public_key(&self.sender).short())?;
+    }
+    for (index, (receiver, amount)) in self.outputs().enumerate() {
+      let separator = if index == 0 { "" } else { "," };
+      write!(f, "{} {} {}", separator, Address::from_public_key(receiver).short(), format_amount(amount))?;
+    }
+    if self.fee > 0 {
+      write!(f, " fee {}", format_amount(self.fee))?;
+    }
+    let verified = if self.verify() { "verified" } else { "unverified" };
+    write!(f, " (uid {}, {})", self.uid, verified)
+  }
+}
+
+// each RSA verify is slow, so a block's worth are spread over every core; all stops
+// handing out work once one fails, and the answer is the same as checking in order
+fn verify_all(transactions: &[Transaction]) -> bool {
+  transactions.par_iter().all(Transaction::verify)
+}
+
+// what a block's miner collects on top of the reward
+fn total_fees(transactions: &[Transaction]) -> u64 {
+  transactions.iter().filter(|transaction| !transaction.is_coinbase()).fold(0, |total, transaction| total.saturating_add(transaction.fee))
+}
+
+fn hash_pair(left: &SHA256Hash, right: &SHA256Hash) -> SHA256Hash {
+  sha256_many(&[left.as_bytes(), right.as_bytes()])
+}
+
+// hashes transactions pairwise up to a single root, duplicating the last node of an odd level
+pub fn merkle_root(transactions: &[Transaction]) -> SHA256Hash {
+  if transactions.is_empty() {
+    return ZERO_HASH;
+  }
+  let mut level: Vec<SHA256Hash> = transactions.iter().map(Transaction::hash).collect();
+  while level.len() > 1 {
+    level = next_merkle_level(&level);
+  }
+  level[0]
+}
+
+fn next_merkle_level(level: &[SHA256Hash]) -> Vec<SHA256Hash> {
+  level.chunks(2).map(|pair| hash_pair(&pair[0], pair.last().unwrap())).collect()
+}
+
+// sibling hashes from the leaf at `index` up to the root
+pub fn merkle_proof(transactions: &[Transaction], index: usize) -> Vec<SHA256Hash> {
+  assert!(index < transactions.len(), "no transaction at index {}", index);
+  let mut level: Vec<SHA256Hash> = transactions.iter().map(Transaction::hash).collect();
+  let mut index = index;
+  let mut proof = Vec::new();
+  while level.len() > 1 {
+    let sibling = if index.is_multiple_of(2) { (index + 1).min(level.len() - 1) } else { index - 1 };
+    proof.push(level[sibling]);
+    level = next_merkle_level(&level);
+    index /= 2;
+  }
+  proof
+}
+
+// the index says which side of each pair the running hash sits on
+pub fn verify_merkle_proof(tx_hash: &SHA256Hash, index: usize, proof: &[SHA256Hash], root: &SHA256Hash) -> bool {
+  let mut hash = *tx_hash;
+  let mut index = index;
+  for sibling in proof {
+    hash = if index.is_multiple_of(2) { hash_pair(&hash, sibling) } else { hash_pair(sibling, &hash) };
+    index /= 2;
+  }
+  hashes_equal(&hash, root)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MineError {
+  NonceExhausted,
+}
+
+impl fmt::Display for MineError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MineError::NonceExhausted => write!(f, "no nonce satisfies the difficulty"),
+    }
+  }
+}
+
+impl std::error::Error for MineError {}
+
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+  InvalidTransaction { index: usize },
+  HashMismatch { index: usize },
+  BrokenLink { index: usize },
+  InsufficientWork { index: usize },
+  WrongGenesis,
+  WrongReward { index: usize },
+  TooManyTransactions { index: usize },
+  CheckpointMismatch { index: usize },
+  TimestampTooEarly { index: usize },
+  TimestampTooFarAhead { index: usize },
+  ExpiredTransaction { index: usize },
+  ZeroAmount { index: usize },
+  ForeignUid { index: usize },
+  CoinbaseNotToMiner { index: usize },
+  Unauthorized { index: usize },
+  BadBlockSignature { index: usize },
+  BadCoinbase { index: usize },
+  WrongDifficulty { index: usize },
+  Overspend { index: usize },
+  DuplicateUid { index: usize },
+  PaysSentinel { index: usize },
+  BadSnapshot,
+}
+
+impl fmt::Display for ValidationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ValidationError::InvalidTransaction { index } => write!(f, "block {} contains a transaction with a bad signature", index),
+      ValidationError::HashMismatch { index } => write!(f, "block {} has a hash that doesn't match its contents", index),
+      ValidationError::BrokenLink { index } => write!(f, "block {} doesn't point to the previous block", index),
+      ValidationError::InsufficientWork { index } => write!(f, "block {} doesn't meet its difficulty", index),
+      ValidationError::WrongGenesis => write!(f, "chain doesn't start with the genesis block"),
+      ValidationError::WrongReward { index } => write!(f, "block {} mints more or less than its reward and fees", index),
+      ValidationError::TooManyTransactions { index } => write!(f, "block {} has more transactions than the chain allows", index),
+      ValidationError::CheckpointMismatch { index } => write!(f, "block {} doesn't match the checkpoint for its height", index),
+      ValidationError::TimestampTooEarly { index } => write!(f, "block {} isn't newer than the median of the blocks before it", index),
+      ValidationError::TimestampTooFarAhead { index } => write!(f, "block {} claims a time too far in the future", index),
+      ValidationError::ExpiredTransaction { index } => write!(f, "block {} contains a transaction past its last valid height", index),
+      ValidationError::ZeroAmount { index } => write!(f, "block {} contains a transaction paying nothing", index),
+      ValidationError::ForeignUid { index } => write!(f, "block {} contains a transaction whose uid doesn't encode its sender", index),
+      ValidationError::CoinbaseNotToMiner { index } => write!(f, "block {} pays its reward to someone other than its miner", index),
+      ValidationError::Unauthorized { index } => write!(f, "block {} isn't mined by an authorized validator", index),
+      ValidationError::BadBlockSignature { index } => write!(f, "block {} isn't signed by its miner", index),
+      ValidationError::BadCoinbase { index } => write!(f, "block {} doesn't start with a single correct coinbase", index),
+      ValidationError::WrongDifficulty { index } => write!(f, "block {} isn't mined at the difficulty retargeting calls for", index),
+      ValidationError::Overspend { index } => write!(f, "block {} contains a transaction spending more than its sender has", index),
+      ValidationError::DuplicateUid { index } => write!(f, "block {} contains a transaction uid that was already used", index),
+      ValidationError::PaysSentinel { index } => write!(f, "block {} contains a transaction paying the sentinel key", index),
+      ValidationError::BadSnapshot => write!(f, "pruned snapshot doesn't match the blocks it stands in for"),
+    }
+  }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+  Mine(MineError),
+  InsufficientFunds { uid: u64 },
+  DuplicateUid { uid: u64 },
+  Invalid(ValidationError),
+  BadCoinbase,
+  WrongDifficulty { expected: u32, found: u32 },
+  PaysSentinel { uid: u64 },
+  Expired { uid: u64 },
+  ZeroAmount { uid: u64 },
+  ForeignUid { uid: u64 },
+}
+
+impl fmt::Display for BlockError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      BlockError::Mine(error) => write!(f, "failed to mine block: {}", error),
+      BlockError::InsufficientFunds { uid } => write!(f, "transaction {} spends more than its sender has", uid),
+      BlockError::DuplicateUid { uid } => write!(f, "a transaction with uid {} is already in the chain", uid),
+      BlockError::Invalid(error) => write!(f, "{}", error),
+      BlockError::BadCoinbase => write!(f, "block doesn't start with a single correct coinbase"),
+      BlockError::WrongDifficulty { expected, found } => write!(f, "block was mined at difficulty {} instead of {}", found, expected),
+      BlockError::PaysSentinel { uid } => write!(f, "transaction {} pays the sentinel key", uid),
+      BlockError::Expired { uid } => write!(f, "transaction {} can no longer be mined", uid),
+      BlockError::ZeroAmount { uid } => write!(f, "transaction {} pays nothing to one of its outputs", uid),
+      BlockError::ForeignUid { uid } => write!(f, "transaction {} has a uid that doesn't encode its sender", uid),
+    }
+  }
+}
+
+impl std::error::Error for BlockError {}
+
+impl BlockError {
+  // how validate reports the same problem with the block at index
+  fn at(self, index: usize) -> ValidationError {
+    match self {
+      BlockError::Invalid(error) => error,
+      BlockError::BadCoinbase => ValidationError::BadCoinbase { index },
+      BlockError::WrongDifficulty { .. } => ValidationError::WrongDifficulty { index },
+      BlockError::InsufficientFunds { .. } => ValidationError::Overspend { index },
+      BlockError::DuplicateUid { .. } => ValidationError::DuplicateUid { index },
+      BlockError::PaysSentinel { .. } => ValidationError::PaysSentinel { index },
+      BlockError::Expired { .. } => ValidationError::ExpiredTransaction { index },
+      BlockError::ZeroAmount { .. } => ValidationError::ZeroAmount { index },
+      BlockError::ForeignUid { .. } => ValidationError::ForeignUid { index },
+      BlockError::Mine(error) => panic!("checking a block never mines it, but got {}", error),
+    }
+  }
+
+  // the transaction the chain has moved past and that so can never be mined, when the
+  // error is down to one transaction
+  pub fn unminable_uid(&self) -> Option<u64> {
+    match self {
+      BlockError::DuplicateUid { uid } | BlockError::InsufficientFunds { uid } | BlockError::PaysSentinel { uid } | BlockError::Expired { uid } | BlockError::ZeroAmount { uid } | BlockError::ForeignUid { uid } => Some(*uid),
+      _ => None,
+    }
+  }
+}
+
+impl From<MineError> for BlockError {
+  fn from(error: MineError) -> Self {
+    BlockError::Mine(error)
+  }
+}
+
+impl From<ValidationError> for BlockError {
+  fn from(error: ValidationError) -> Self {
+    BlockError::Invalid(error)
+  }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Block {
+  transactions: Vec<Transaction>,
+  nonce: u64,
+  #[serde(with = "public_key_der")]
+  miner: RsaPublicKey,
+  previous_hash: SHA256Hash,
+  timestamp: u64,
+  difficulty: u32,
+  hash: SHA256Hash,
+  // set once the transactions have been pruned away, so the hash can still be checked
+  #[serde(default)]
+  pruned_merkle_root: Option<SHA256Hash>,
+  // the miner's signature of the hash, so only the holder of the key can claim a block;
+  // the genesis block is the only one without
+  #[serde(default)]
+  signature: Vec<u8>,
+}
+
+impl Block {
+  pub fn transactions(&self) -> &[Transaction] {
+    &self.transactions
+  }
+
+  pub fn signature(&self) -> &[u8] {
+    &self.signature
+  }
+
+  // signs the hash, which covers everything else in the block, as its miner
+  pub fn sign(&mut self, private_key: &RsaPrivateKey) {
+    self.signature = sign_message(self.hash.0, private_key);
+  }
+
+  pub fn verify_signature(&self) -> bool {
+    verify_message(self.hash.0, &self.signature, &self.miner)
+  }
+
+  pub fn nonce(&self) -> u64 {
+    self.nonce
+  }
+
+  pub fn miner(&self) -> &RsaPublicKey {
+    &self.miner
+  }
+
+  pub fn previous_hash(&self) -> &SHA256Hash {
+    &self.previous_hash
+  }
+
+  pub fn timestamp(&self) -> u64 {
+    self.timestamp
+  }
+
+  pub fn difficulty(&self) -> u32 {
+    self.difficulty
+  }
+
+  /// ```
+  /// use shitcoin::blockchain::Blockchain;
+  ///
+  /// let chain = Blockchain::default();
+  /// let genesis = chain.last_block();
+  /// assert_eq!(*genesis.hash(), chain.last_hash());
+  /// ```
+  pub fn hash(&self) -> &SHA256Hash {
+    &self.hash
+  }
+
+  // a pruned block has no transactions left, only their merkle root
+  pub fn is_pruned(&self) -> bool {
+    self.pruned_merkle_root.is_some()
+  }
+
+  pub fn merkle_root(&self) -> SHA256Hash {
+    self.pruned_merkle_root.unwrap_or_else(|| merkle_root(&self.transactions))
+  }
+
+  // coins this block minted on top of the fees it collected
+  fn minted(&self) -> u64 {
+    match self.transactions.split_first() {
+      Some((coinbase, transactions)) if coinbase.is_coinbase() => coinbase.amount.saturating_sub(total_fees(transactions)),
+      _ => 0,
+    }
+  }
+
+  // compact binary encoding, for when JSON is too big
+  pub fn to_bytes(&self) -> Vec<u8> {
+    bincode::serialize(self).expect("failed to encode block")
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Block> {
+    bincode::deserialize(bytes)
+  }
+
+  // recompute the hash from the block's own fields, no searching involved
+  pub fn verify_pow(&self) -> bool {
+    let target = target_from_leading_zero_bits(self.difficulty);
+    let (meets_target, hash) = Self::check_block(&self.merkle_root(), &public_key_der(&self.miner), self.nonce, &self.previous_hash, self.timestamp, &target);
+    meets_target && hashes_equal(&hash, &self.hash)
+  }
+
+  // miner is the key's public_key_der, which is encoded once by whoever is trying nonces
+  #[inline(always)]
+  fn check_block(merkle_root: &SHA256Hash, miner: &[u8], nonce: u64, previous_hash: &SHA256Hash, timestamp: u64, target: &SHA256Hash) -> (bool, SHA256Hash) {
+    count_hash();
+    let header = [merkle_root.as_bytes(), miner, &nonce.to_be_bytes(), previous_hash.as_bytes(), &timestamp.to_be_bytes(), target.as_bytes()].concat();
+    let hash = block_hash(&header, DOUBLE_SHA256_BLOCKS);
+    // byte arrays compare lexicographically, which is big-endian numeric order
+    (hash <= *target, hash)
+  }
+
+  fn mine_block(transactions: &[Transaction], miner: &RsaPublicKey, previous_hash: &SHA256Hash, timestamp: u64, difficulty: u32) -> Result<(u64, SHA256Hash), MineError> {
+    Self::mine_block_parallel(transactions, miner, previous_hash, timestamp, difficulty, mining_threads())
+  }
+
+  fn mine_block_parallel(transactions: &[Transaction], miner: &RsaPublicKey, previous_hash: &SHA256Hash, timestamp: u64, difficulty: u32, num_threads: usize) -> Result<(u64, SHA256Hash), MineError> {
+    let never = AtomicBool::new(false);
+    Self::mine_until_cancelled(transactions, miner, previous_hash, timestamp, difficulty, num_threads, &never)?
+      .ok_or(MineError::NonceExhausted)
+  }
+
+  // gives up with Ok(None) as soon as cancel is set, e.g. because someone else found the next block first
+  pub fn mine_cancellable(transactions: &[Transaction], miner: &RsaPublicKey, previous_hash: &SHA256Hash, timestamp: u64, difficulty: u32, cancel: &AtomicBool) -> Result<Option<(u64, SHA256Hash)>, MineError> {
+    Self::mine_until_cancelled(transactions, miner, previous_hash, timestamp, difficulty, mining_threads(), cancel)
+  }
+
+  // thread i tries nonces i, i + num_threads, ... and the first to find one stops the rest
+  fn mine_until_cancelled(transactions: &[Transaction], miner: &RsaPublicKey, previous_hash: &SHA256Hash, timestamp: u64, difficulty: u32, num_threads: usize, cancel: &AtomicBool) -> Result<Option<(u64, SHA256Hash)>, MineError> {
+    let num_threads = num_threads.max(1);
+    let found = AtomicBool::new(false);
+    let found = std::thread::scope(|scope| {
+      let workers: Vec<_> = (0..num_threads).map(|offset| {
+        let found = &found;
+        scope.spawn(move || {
+          let nonces = (offset as u64..=u64::MAX)
+            .step_by(num_threads)
+            .take_while(|_| !found.load(Ordering::Relaxed) && !cancel.load(Ordering::Relaxed));
+          let result = Self::mine_nonces(transactions, miner, previous_hash, timestamp, difficulty, nonces);
+          if result.is_ok() {
+            found.store(true, Ordering::Relaxed);
+          }
+          result
+        })
+      }).collect();
+      workers.into_iter()
+        .filter_map(|worker| worker.join().expect("mining thread panicked").ok())
+        .next()
+    });
+    match found {
+      Some(found) => Ok(Some(found)),
+      None if cancel.load(Ordering::Relaxed) => Ok(None),
+      None => Err(MineError::NonceExhausted),
+    }
+  }
+
+  // mines on one thread, calling on_progress with the number of nonces tried so far after every
+  // `every` of them; the callback only costs a counter check per nonce
+  pub fn mine_with_progress(transactions: &[Transaction], miner: &RsaPublicKey, previous_hash: &SHA256Hash, timestamp: u64, difficulty: u32, every: u64, mut on_progress: impl FnMut(u64)) -> Result<(u64, SHA256Hash), MineError> {
+    let every = every.max(1);
+    let mut attempts = 0_u64;
+    let nonces = (0..=u64::MAX).inspect(|_| {
+      attempts += 1;
+      if attempts.is_multiple_of(every) {
+        on_progress(attempts);
+      }
+    });
+    Self::mine_nonces(transactions, miner, previous_hash, timestamp, difficulty, nonces)
+  }
+
+  fn mine_nonces(transactions: &[Transaction], miner: &RsaPublicKey, previous_hash: &SHA256Hash, timestamp: u64, difficulty: u32, nonces: impl Iterator<Item = u64>) -> Result<(u64, SHA256Hash), MineError> {
+    let target = target_from_leading_zero_bits(difficulty);
+    let merkle_root = merkle_root(transactions);
+    let miner = public_key_der(miner);
+    for i in nonces {
+      let (is_correct, hash) = Self::check_block(&merkle_root, &miner, i, previous_hash, timestamp, &target);
+      if is_correct {
+        return Ok((i, hash));
+      }
+    }
+    Err(MineError::NonceExhausted)
+  }
+
+  // the genesis block isn't mined, it's the same on every node: no transactions,
+  // sentinel miner, zero previous hash, timestamp and nonce
+  fn genesis(params: &ChainParams) -> Self {
+    let miner = sentinel_key();
+    let nonce = params.genesis_nonce();
+    let (_, hash) = Self::check_block(&merkle_root(&[]), &public_key_der(&miner), nonce, &ZERO_HASH, 0, &target_from_leading_zero_bits(0));
+    Block { transactions: Vec::new(), nonce, miner, previous_hash: ZERO_HASH, timestamp: 0, difficulty: 0, hash, pruned_merkle_root: None, signature: Vec::new() }
+  }
+
+  pub fn header(&self) -> BlockHeader {
+    BlockHeader {
+      previous_hash: self.previous_hash,
+      merkle_root: self.merkle_root(),
+      timestamp: self.timestamp,
+      nonce: self.nonce,
+      difficulty: self.difficulty,
+      miner: self.miner.clone(),
+    }
+  }
+}
+
+// everything the block hash covers, without the transactions; the miner has to come
+// along because it's hashed too
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+  previous_hash: SHA256Hash,
+  merkle_root: SHA256Hash,
+  timestamp: u64,
+  nonce: u64,
+  difficulty: u32,
+  #[serde(with = "public_key_der")]
+  miner: RsaPublicKey,
+}
+
+impl BlockHeader {
+  pub fn previous_hash(&self) -> &SHA256Hash {
+    &self.previous_hash
+  }
+
+  pub fn merkle_root(&self) -> &SHA256Hash {
+    &self.merkle_root
+  }
+
+  pub fn timestamp(&self) -> u64 {
+    self.timestamp
+  }
+
+  pub fn nonce(&self) -> u64 {
+    self.nonce
+  }
+
+  pub fn difficulty(&self) -> u32 {
+    self.difficulty
+  }
+
+  pub fn miner(&self) -> &RsaPublicKey {
+    &self.miner
+  }
+
+  pub fn hash(&self) -> SHA256Hash {
+    self.check().1
+  }
+
+  // whether the hash meets the difficulty the header claims
+  pub fn verify_pow(&self) -> bool {
+    self.check().0
+  }
+
+  fn check(&self) -> (bool, SHA256Hash) {
+    let target = target_from_leading_zero_bits(self.difficulty);
+    Block::check_block(&self.merkle_root, &public_key_der(&self.miner), self.nonce, &self.previous_hash, self.timestamp, &target)
+  }
+}
+
+// a block waiting for its nonce, see Blockchain::block_template
+pub struct BlockTemplate {
+  transactions: Vec<Transaction>,
+  miner: RsaPublicKey,
+  previous_hash: SHA256Hash,
+  timestamp: u64,
+  difficulty: u32,
+}
+
+impl BlockTemplate {
+  // the user transactions, without the coinbase
+  pub fn transactions(&self) -> &[Transaction] {
+    &self.transactions[1..]
+  }
+
+  // Ok(None) if cancel was set first
+  pub fn mine(self, cancel: &AtomicBool) -> Result<Option<Block>, MineError> {
+    let found = Block::mine_cancellable(&self.transactions, &self.miner, &self.previous_hash, self.timestamp, self.difficulty, cancel)?;
+    Ok(found.map(|(nonce, hash)| self.into_block(nonce, hash)))
+  }
+
+  fn into_block(self, nonce: u64, hash: SHA256Hash) -> Block {
+    let BlockTemplate { transactions, miner, previous_hash, timestamp, difficulty } = self;
+    Block { transactions, nonce, miner, previous_hash, timestamp, difficulty, hash, pruned_merkle_root: None, signature: Vec::new() }
+  }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(io::Error),
+  Parse(serde_json::Error),
+  Invalid(ValidationError),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::Io(error) => write!(f, "failed to read chain file: {}", error),
+      LoadError::Parse(error) => write!(f, "failed to parse chain file: {}", error),
+      LoadError::Invalid(error) => write!(f, "chain file is invalid: {}", error),
+    }
+  }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+  fn from(error: io::Error) -> Self {
+    LoadError::Io(error)
+  }
+}
+
+impl From<serde_json::Error> for LoadError {
+  fn from(error: serde_json::Error) -> Self {
+    LoadError::Parse(error)
+  }
+}
+
+impl From<ValidationError> for LoadError {
+  fn from(error: ValidationError) -> Self {
+    LoadError::Invalid(error)
+  }
+}
+
+// the sender pays for everything the transaction costs and each output is credited;
+// signed so a balance can dip below zero partway through
+pub(crate) fn apply_to_balances(balances: &mut HashMap<Address, i128>, transaction: &Transaction) {
+  *balances.entry(Address::from_public_key(&transaction.sender)).or_insert(0) -= transaction.cost() as i128;
+  for (receiver, amount) in transaction.outputs() {
+    *balances.entry(Address::from_public_key(receiver)).or_insert(0) += amount as i128;
+  }
+}
+
+// undoes apply_to_balances
+pub(crate) fn revert_from_balances(balances: &mut HashMap<Address, i128>, transaction: &Transaction) {
+  *balances.entry(Address::from_public_key(&transaction.sender)).or_insert(0) += transaction.cost() as i128;
+  for (receiver, amount) in transaction.outputs() {
+    *balances.entry(Address::from_public_key(receiver)).or_insert(0) -= amount as i128;
+  }
+}
+
+// the sentinel's balance only ever goes down, and nobody can spend it anyway
+pub(crate) fn positive_balances(balances: HashMap<Address, i128>) -> HashMap<Address, u64> {
+  balances.into_iter()
+    .filter_map(|(address, balance)| u64::try_from(balance).ok().filter(|balance| *balance > 0).map(|balance| (address, balance)))
+    .collect()
+}
+
+// why a transaction couldn't go in the next block
+#[derive(Debug, PartialEq)]
+pub enum RejectReason {
+  SentinelKey,
+  ZeroAmount,
+  Expired,
+  InvalidSignature,
+  ForeignUid,
+  DuplicateUid,
+  InsufficientFunds,
+}
+
+impl fmt::Display for RejectReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      RejectReason::SentinelKey => write!(f, "transaction sends from or to the sentinel key"),
+      RejectReason::ZeroAmount => write!(f, "transaction pays nothing to one of its outputs"),
+      RejectReason::Expired => write!(f, "transaction can no longer be mined"),
+      RejectReason::InvalidSignature => write!(f, "transaction signature doesn't verify"),
+      RejectReason::ForeignUid => write!(f, "transaction uid doesn't encode its sender"),
+      RejectReason::DuplicateUid => write!(f, "transaction uid has already been used"),
+      RejectReason::InsufficientFunds => write!(f, "sender can't cover the transaction"),
+    }
+  }
+}
+
+impl std::error::Error for RejectReason {}
+
+#[derive(Debug, PartialEq)]
+pub enum ReorgError {
+  NoBlocks,
+  UnknownAncestor,
+  // undoing a block means knowing its transactions, and pruned blocks have none
+  AncestorPruned,
+  NotEnoughWork,
+  Invalid(BlockError),
+}
+
+impl fmt::Display for ReorgError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ReorgError::NoBlocks => write!(f, "branch has no blocks"),
+      ReorgError::UnknownAncestor => write!(f, "branch doesn't build on any block in the chain"),
+      ReorgError::AncestorPruned => write!(f, "branch forks off below the pruned height"),
+      ReorgError::NotEnoughWork => write!(f, "branch has no more work than the chain"),
+      ReorgError::Invalid(error) => write!(f, "branch is invalid: {}", error),
+    }
+  }
+}
+
+impl std::error::Error for ReorgError {}
+
+impl From<BlockError> for ReorgError {
+  fn from(error: BlockError) -> Self {
+    ReorgError::Invalid(error)
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Blockchain {
+  blocks: Vec<Block>,
+  #[serde(flatten)]
+  params: ChainParams,
+  // uids of every non-coinbase transaction in the chain, rebuilt on load
+  #[serde(skip)]
+  seen_uids: HashSet<u64>,
+  // blocks are only appended or rolled back, so once this many leading blocks validate they stay valid
+  #[serde(skip)]
+  validated: AtomicUsize,
+  #[serde(default)]
+  snapshot: PruneSnapshot,
+  // what every address holds with all blocks applied, so balances are a lookup; rebuilt on load
+  #[serde(skip)]
+  balances: HashMap<Address, i128>,
+}
+
+// what pruned blocks leave behind, so balances and uid checks still account for them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct PruneSnapshot {
+  // blocks below this height have been pruned
+  height: u64,
+  balances: HashMap<Address, u64>,
+  uids: HashSet<u64>,
+  supply: u64,
+}
+
+impl Blockchain {
+  pub fn new(params: ChainParams) -> Self {
+    Blockchain {
+      blocks: vec![Block::genesis(&params)],
+      params,
+      seen_uids: HashSet::new(),
+      validated: AtomicUsize::new(0),
+      snapshot: PruneSnapshot::default(),
+      balances: HashMap::new(),
+    }
+  }
+
+  // the default params apart from the difficulty
+  pub fn with_difficulty(difficulty: u32) -> Self {
+    Self::new(ChainParams { initial_difficulty: difficulty, ..ChainParams::default() })
+  }
+
+  pub fn params(&self) -> &ChainParams {
+    &self.params
+  }
+
+  // fork choice: take candidate only if it has more work and every block in it would
+  // have been accepted by append_block under our own rules
+  pub fn replace_if_better(&mut self, candidate: Blockchain) -> bool {
+    if candidate.total_work() <= self.total_work() {
+      return false;
+    }
+    match self.replay(candidate) {
+      Ok(chain) => {
+        *self = chain;
+        true
+      }
+      Err(_) => false,
+    }
+  }
+
+  // a block at difficulty d takes 2^d hashes on average to find
+  pub fn total_work(&self) -> u128 {
+    self.blocks.iter()
+      .map(|block| 1_u128.checked_shl(block.difficulty).unwrap_or(u128::MAX))
+      .fold(0, u128::saturating_add)
+  }
+
+  fn replay(&self, candidate: Blockchain) -> Result<Blockchain, BlockError> {
+    let mut chain = Blockchain::new(self.params.clone());
+    let mut blocks = candidate.blocks.into_iter();
+    if blocks.next().map(|block| block.hash) != Some(chain.last_hash()) {
+      return Err(BlockError::Invalid(ValidationError::WrongGenesis));
+    }
+    for block in blocks {
+      chain.append_block(block)?;
+    }
+    Ok(chain)
+  }
+
+  // switch to a branch that forks off our chain, as long as it has more work and every
+  // block in it is accepted; returns the transactions from the abandoned blocks that the
+  // branch didn't include, so they can go back in the mempool
+  pub fn reorg_to(&mut self, new_tip_blocks: Vec<Block>) -> Result<Vec<Transaction>, ReorgError> {
+    let first = new_tip_blocks.first().ok_or(ReorgError::NoBlocks)?;
+    let ancestor = self.blocks.iter().rposition(|block| hashes_equal(&block.hash, &first.previous_hash)).ok_or(ReorgError::UnknownAncestor)?;
+    if ((ancestor + 1) as u64) < self.snapshot.height {
+      return Err(ReorgError::AncestorPruned);
+    }
+    let mut branch = self.truncated(ancestor + 1);
+    for block in new_tip_blocks {
+      branch.append_block(block)?;
+    }
+    if branch.total_work() <= self.total_work() {
+      return Err(ReorgError::NotEnoughWork);
+    }
+    let included: HashSet<SHA256Hash> = branch.blocks[ancestor + 1..].iter().flat_map(|block| &block.transactions).map(Transaction::hash).collect();
+    let abandoned = std::mem::replace(self, branch).blocks.split_off(ancestor + 1);
+    Ok(abandoned.into_iter()
+      .flat_map(|block| block.transactions)
+      .filter(|transaction| !transaction.is_coinbase() && !included.contains(&transaction.hash()))
+      .collect())
+  }
+
+  // a copy of the first len blocks, as if nothing after them had been appended
+  fn truncated(&self, len: usize) -> Blockchain {
+    let mut seen_uids = self.seen_uids.clone();
+    let mut balances = self.balances.clone();
+    // backwards, so each transaction is undone on the balances it was applied to
+    for transaction in self.blocks[len..].iter().flat_map(|block| &block.transactions).rev() {
+      if !transaction.is_coinbase() {
+        seen_uids.remove(&transaction.uid);
+      }
+      revert_from_balances(&mut balances, transaction);
+    }
+    Blockchain {
+      blocks: self.blocks[..len].to_vec(),
+      params: self.params.clone(),
+      seen_uids,
+      validated: AtomicUsize::new(self.validated.load(Ordering::Relaxed).min(len)),
+      snapshot: self.snapshot.clone(),
+      balances,
+    }
+  }
+
+  // mined and signed by miner, who the reward goes to
+  pub fn add_block(&mut self, transactions: Vec<Transaction>, miner: &RsaPrivateKey) -> Result<(), BlockError> {
+    self.add_block_at(transactions, miner, unix_time())
+  }
+
+  fn add_block_at(&mut self, transactions: Vec<Transaction>, miner: &RsaPrivateKey, timestamp: u64) -> Result<(), BlockError> {
+    let template = self.block_template_at(transactions, RsaPublicKey::from(miner), timestamp)?;
+    let (nonce, hash) = Block::mine_block(&template.transactions, &template.miner, &template.previous_hash, template.timestamp, template.difficulty)?;
+    let mut block = template.into_block(nonce, hash);
+    block.sign(miner);
+    self.push_block(block);
+    info!("block mined at height {} with nonce {}", self.blocks.len() - 1, nonce);
+    Ok(())
+  }
+
+  // everything add_block checks and puts in the next block, ready to be mined without
+  // holding on to the chain; append_block takes the result
+  pub fn block_template(&self, transactions: Vec<Transaction>, miner: RsaPublicKey) -> Result<BlockTemplate, BlockError> {
+    self.block_template_at(transactions, miner, unix_time())
+  }
+
+  fn block_template_at(&self, transactions: Vec<Transaction>, miner: RsaPublicKey, timestamp: u64) -> Result<BlockTemplate, BlockError> {
+    // caught before anything's mined, since no amount of work makes up for it
+    if let ConsensusMode::ProofOfAuthority { validators } = &self.params.consensus {
+      if !validators.contains(&miner) {
+        return Err(ValidationError::Unauthorized { index: self.blocks.len() }.into());
+      }
+    }
+    if transactions.len() > self.params.max_block_txs {
+      return Err(ValidationError::TooManyTransactions { index: self.blocks.len() }.into());
+    }
+    self.check_transactions(&transactions)?;
+    let height = self.blocks.len() as u64;
+    // blocks mined in the same second would otherwise fail the median time check
+    let timestamp = timestamp.max(Self::median_time_past(&self.blocks) + 1);
+    let coinbase = Transaction::coinbase(miner.clone(), self.params.block_reward(height) + total_fees(&transactions), height);
+    let transactions: Vec<Transaction> = std::iter::once(coinbase).chain(transactions).collect();
+    Ok(BlockTemplate { transactions, miner, previous_hash: self.last_hash(), timestamp, difficulty: self.next_difficulty() })
+  }
+
+  // append a block mined somewhere else, after checking it the same way add_block builds one
+  pub fn append_block(&mut self, block: Block) -> Result<(), BlockError> {
+    // checked up front so an oversized block costs nothing to turn away
+    Self::check_transaction_count(&self.params, &block, self.blocks.len())?;
+    self.check_against_state(&block)?;
+    Self::check_timestamp(&self.blocks, &block)?;
+    Self::check_linked_block(&self.params, &block, self.blocks.len(), &self.last_hash())?;
+    self.push_block(block);
+    Ok(())
+  }
+
+  // the coinbase, spends and difficulty of block as the next one on this chain, which
+  // unlike what check_linked_block looks at depend on every block before it
+  fn check_against_state(&self, block: &Block) -> Result<(), BlockError> {
+    let (coinbase, transactions) = block.transactions.split_first().ok_or(BlockError::BadCoinbase)?;
+    let correct_coinbase = coinbase.is_coinbase()
+      && coinbase.receiver == block.miner
+      && coinbase.extra_outputs.is_empty()
+      && coinbase.uid == self.blocks.len() as u64;
+    if !correct_coinbase {
+      return Err(BlockError::BadCoinbase);
+    }
+    self.check_transactions(transactions)?;
+    let expected = self.next_difficulty();
+    if block.difficulty != expected {
+      return Err(BlockError::WrongDifficulty { expected, found: block.difficulty });
+    }
+    Ok(())
+  }
+
+  fn push_block(&mut self, block: Block) {
+    self.seen_uids.extend(block.transactions.iter().filter(|transaction| !transaction.is_coinbase()).map(|transaction| transaction.uid));
+    for transaction in &block.transactions {
+      apply_to_balances(&mut self.balances, transaction);
+    }
+    self.blocks.push(block);
+  }
+
+  // whether the transaction could go in the next block on its own; the mempool and
+  // block checks both come through here
+  pub fn can_accept(&self, transaction: &Transaction) -> Result<(), RejectReason> {
+    self.check_spend(transaction, &HashMap::new(), &HashSet::new())?;
+    if !transaction.verify() {
+      return Err(RejectReason::InvalidSignature);
+    }
+    Ok(())
+  }
+
+  // everything but the signature, which blocks check for all transactions at once;
+  // pending and block_uids carry what earlier transactions in the same block changed
+  fn check_spend(&self, transaction: &Transaction, pending: &HashMap<Address, i128>, block_uids: &HashSet<u64>) -> Result<(), RejectReason> {
+    // only the coinbase may come from the sentinel key, and nothing may go to it
+    if transaction.is_coinbase() || transaction.pays_sentinel() {
+      return Err(RejectReason::SentinelKey);
+    }
+    if !transaction.has_valid_amounts() {
+      return Err(RejectReason::ZeroAmount);
+    }
+    if !transaction.is_valid_at(self.blocks.len() as u64) {
+      return Err(RejectReason::Expired);
+    }
+    if self.params.derived_uids && !transaction.uid_encodes_sender() {
+      return Err(RejectReason::ForeignUid);
+    }
+    // a uid can only be spent once, so stop replays of an already mined transaction
+    if self.seen_uids.contains(&transaction.uid) || block_uids.contains(&transaction.uid) {
+      return Err(RejectReason::DuplicateUid);
+    }
+    let sender = Address::from_public_key(&transaction.sender);
+    let available = self.balance_of(&transaction.sender) as i128 + pending.get(&sender).copied().unwrap_or(0);
+    if transaction.cost() as i128 > available {
+      return Err(RejectReason::InsufficientFunds);
+    }
+    Ok(())
+  }
+
+  // the user transactions of the next block, in order, so each sender has to be able to
+  // cover their transaction counting earlier ones in the same block
+  fn check_transactions(&self, transactions: &[Transaction]) -> Result<(), BlockError> {
+    // signed so a sender's pending change can go below zero within the block
+    let mut pending: HashMap<Address, i128> = HashMap::new();
+    let mut block_uids = HashSet::new();
+    for transaction in transactions {
+      let uid = transaction.uid;
+      self.check_spend(transaction, &pending, &block_uids).map_err(|reason| match reason {
+        RejectReason::SentinelKey if transaction.is_coinbase() => BlockError::BadCoinbase,
+        RejectReason::SentinelKey => BlockError::PaysSentinel { uid },
+        RejectReason::ZeroAmount => BlockError::ZeroAmount { uid },
+        RejectReason::Expired => BlockError::Expired { uid },
+        RejectReason::InvalidSignature => ValidationError::InvalidTransaction { index: self.blocks.len() }.into(),
+        RejectReason::ForeignUid => BlockError::ForeignUid { uid },
+        RejectReason::DuplicateUid => BlockError::DuplicateUid { uid },
+        RejectReason::InsufficientFunds => BlockError::InsufficientFunds { uid },
+      })?;
+      block_uids.insert(uid);
+      *pending.entry(Address::from_public_key(&transaction.sender)).or_insert(0) -= transaction.cost() as i128;
+      for (receiver, amount) in transaction.outputs() {
+        *pending.entry(Address::from_public_key(receiver)).or_insert(0) += amount as i128;
+      }
+    }
+    Ok(())
+  }
+
+  // the difficulty the next block has to be mined at
+  pub fn next_difficulty(&self) -> u32 {
+    // the genesis block isn't mined, so it doesn't count towards retargeting
+    let mined = &self.blocks[1..];
+    if let ConsensusMode::ProofOfAuthority { .. } = self.params.consensus {
+      return 0;
+    }
+    let current = match mined.last() {
+      Some(block) => block.difficulty,
+      None => return self.params.initial_difficulty,
+    };
+    if !mined.len().is_multiple_of(RETARGET_INTERVAL) {
+      return current;
+    }
+    let window = &mined[mined.len() - RETARGET_INTERVAL..];
+    let elapsed = window[window.len() - 1].timestamp.saturating_sub(window[0].timestamp);
+    let expected = (RETARGET_INTERVAL as u64 - 1) * self.params.target_block_seconds;
+    if elapsed < expected {
+      current + 1
+    } else if elapsed > expected {
+      current.saturating_sub(1)
+    } else {
+      current
+    }
+  }
+
+  pub fn genesis_hash(&self) -> SHA256Hash {
+    self.params.genesis_hash()
+  }
+
+  // everything append_block would have checked, for every block
+  pub fn validate(&self) -> Result<(), ValidationError> {
+    self.replayed()?;
+    self.validated.store(self.blocks.len(), Ordering::Relaxed);
+    Ok(())
+  }
+
+  // the chain built up again one block at a time from the pruned snapshot, checking each
+  // block on the way; ones validate already passed are applied without checking again
+  fn replayed(&self) -> Result<Blockchain, ValidationError> {
+    let validated = self.validated.load(Ordering::Relaxed);
+    if validated == 0 && self.blocks.first().map(|block| block.hash) != Some(self.genesis_hash()) {
+      return Err(ValidationError::WrongGenesis);
+    }
+    if validated == 0 && !self.snapshot_matches_blocks() {
+      return Err(ValidationError::BadSnapshot);
+    }
+    let mut replay = Blockchain {
+      blocks: Vec::with_capacity(self.blocks.len()),
+      params: self.params.clone(),
+      seen_uids: self.snapshot.uids.clone(),
+      validated: AtomicUsize::new(0),
+      snapshot: self.snapshot.clone(),
+      balances: self.snapshot.balances.iter().map(|(address, balance)| (*address, *balance as i128)).collect(),
+    };
+    for (index, block) in self.blocks.iter().enumerate() {
+      if index >= validated {
+        Self::check_linked_block(&self.params, block, index, &replay.blocks.last().map_or(ZERO_HASH, |previous| previous.hash))?;
+        if index > 0 {
+          Self::check_timestamp(&replay.blocks, block)?;
+        }
+        // pruned blocks have no transactions left to check against the state
+        if index > 0 && index as u64 >= self.snapshot.height {
+          replay.check_against_state(block).map_err(|error| error.at(index))?;
+        }
+      }
+      replay.push_block(block.clone());
+    }
+    Ok(replay)
+  }
+
+  // the snapshot is trusted in place of the blocks below its height, so exactly those have to
+  // be pruned, and it can't hold more coins than they could have minted
+  fn snapshot_matches_blocks(&self) -> bool {
+    let snapshot = &self.snapshot;
+    let height = usize::try_from(snapshot.height).unwrap_or(usize::MAX);
+    if height > self.blocks.len() || !self.blocks.iter().enumerate().all(|(index, block)| block.is_pruned() == (index < height)) {
+      return false;
+    }
+    if height == 0 {
+      return *snapshot == PruneSnapshot::default();
+    }
+    // the genesis block mints nothing
+    let minted = (1..snapshot.height).fold(0_u64, |supply, height| supply.saturating_add(self.params.block_reward(height)));
+    let held = snapshot.balances.values().fold(0_u128, |total, balance| total + u128::from(*balance));
+    snapshot.supply == minted && held <= u128::from(snapshot.supply)
+  }
+
+  // the median timestamp of the last MEDIAN_TIME_SPAN blocks, which one miner can't drag around alone
+  fn median_time_past(blocks: &[Block]) -> u64 {
+    let mut timestamps: Vec<u64> = blocks.iter().rev().take(MEDIAN_TIME_SPAN).map(|block| block.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+  }
+
+  // the timestamp of block, to be appended after previous
+  fn check_timestamp(previous: &[Block], block: &Block) -> Result<(), ValidationError> {
+    let index = previous.len();
+    if block.timestamp <= Self::median_time_past(previous) {
+      return Err(ValidationError::TimestampTooEarly { index });
+    }
+    if block.timestamp > unix_time().saturating_add(MAX_FUTURE_BLOCK_SECONDS) {
+      return Err(ValidationError::TimestampTooFarAhead { index });
+    }
+    Ok(())
+  }
+
+  fn check_transaction_count(params: &ChainParams, block: &Block, index: usize) -> Result<(), ValidationError> {
+    // the coinbase doesn't count against the limit
+    let limit = params.max_block_txs + usize::from(index > 0);
+    if block.transactions.len() > limit {
+      return Err(ValidationError::TooManyTransactions { index });
+    }
+    Ok(())
+  }
+
+  // signatures, hash, link and proof of work of the block at index
+  fn check_linked_block(params: &ChainParams, block: &Block, index: usize, previous_hash: &SHA256Hash) -> Result<(), ValidationError> {
+    Self::check_transaction_count(params, block, index)?;
+    if !verify_all(&block.transactions) {
+      return Err(ValidationError::InvalidTransaction { index });
+    }
+    if !block.transactions.iter().all(|transaction| transaction.is_valid_at(index as u64)) {
+      return Err(ValidationError::ExpiredTransaction { index });
+    }
+    if !block.transactions.iter().all(Transaction::has_valid_amounts) {
+      return Err(ValidationError::ZeroAmount { index });
+    }
+    if params.derived_uids && !block.transactions.iter().skip(1).all(Transaction::uid_encodes_sender) {
+      return Err(ValidationError::ForeignUid { index });
+    }
+    if let Some((coinbase, transactions)) = block.transactions.split_first() {
+      if coinbase.is_coinbase() && coinbase.amount != params.block_reward(index as u64) + total_fees(transactions) {
+        return Err(ValidationError::WrongReward { index });
+      }
+      // the reward goes to whoever the proof of work is credited to
+      if coinbase.is_coinbase() && coinbase.receiver != block.miner {
+        return Err(ValidationError::CoinbaseNotToMiner { index });
+      }
+    }
+    let target = target_from_leading_zero_bits(block.difficulty);
+    let (meets_target, hash) = Block::check_block(&block.merkle_root(), &public_key_der(&block.miner), block.nonce, &block.previous_hash, block.timestamp, &target);
+    if !hashes_equal(&hash, &block.hash) {
+      return Err(ValidationError::HashMismatch { index });
+    }
+    if !hashes_equal(&block.previous_hash, previous_hash) {
+      return Err(ValidationError::BrokenLink { index });
+    }
+    match &params.consensus {
+      ConsensusMode::ProofOfWork if !meets_target => return Err(ValidationError::InsufficientWork { index }),
+      ConsensusMode::ProofOfAuthority { validators } if index > 0 && !validators.contains(&block.miner) => {
+        return Err(ValidationError::Unauthorized { index });
+      }
+      _ => {}
+    }
+    // the genesis block is the same for everyone, so nobody signs it
+    if index > 0 && !block.verify_signature() {
+      return Err(ValidationError::BadBlockSignature { index });
+    }
+    Ok(())
+  }
+
+  pub fn balance_of(&self, key: &RsaPublicKey) -> u64 {
+    self.balance_of_address(&Address::from_public_key(key))
+  }
+
+  pub fn balance_of_address(&self, address: &Address) -> u64 {
+    // check_spend never lets a mined chain spend more than it has
+    self.balances.get(address).map_or(0, |balance| u64::try_from(*balance).unwrap_or(0))
+  }
+
+  // coins minted so far; fees only move existing coins so they don't count
+  pub fn total_supply(&self) -> u64 {
+    self.blocks.iter().fold(self.snapshot.supply, |supply, block| supply.saturating_add(block.minted()))
+  }
+
+  // drop the transactions of every block below height, keeping enough of each block
+  // to check its proof of work and link, and folding what they did into a snapshot
+  pub fn prune_before(&mut self, height: u64) {
+    let start = self.snapshot.height as usize;
+    let end = usize::try_from(height).unwrap_or(usize::MAX).min(self.blocks.len());
+    if end <= start {
+      return;
+    }
+    let mut balances: HashMap<Address, i128> = self.snapshot.balances.iter()
+      .map(|(address, balance)| (*address, *balance as i128))
+      .collect();
+    for block in &mut self.blocks[start..end] {
+      self.snapshot.supply = self.snapshot.supply.saturating_add(block.minted());
+      // every one was verified on its way into the chain
+      for transaction in &block.transactions {
+        if !transaction.is_coinbase() {
+          self.snapshot.uids.insert(transaction.uid);
+        }
+        apply_to_balances(&mut balances, transaction);
+      }
+      block.pruned_merkle_root = Some(block.merkle_root());
+      block.transactions = Vec::new();
+    }
+    self.snapshot.balances = positive_balances(balances);
+    self.snapshot.height = end as u64;
+  }
+
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, self)?;
+    Ok(())
+  }
+
+  pub fn load(path: &Path) -> Result<Blockchain, LoadError> {
+    let reader = BufReader::new(File::open(path)?);
+    let chain: Blockchain = serde_json::from_reader(reader)?;
+    // the replay that checks every spend is also what builds the balances and seen uids
+    let replayed = chain.replayed()?;
+    replayed.validated.store(replayed.blocks.len(), Ordering::Relaxed);
+    Ok(replayed)
+  }
+
+  pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+    self.blocks.iter()
+  }
+
+  // every transaction in the chain in order, coinbases included
+  pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+    self.blocks.iter().flat_map(|block| &block.transactions)
+  }
+
+  // the earliest non-coinbase transaction with uid, alongside the index of its block;
+  // coinbases are skipped since their uids are block heights and can collide
+  pub fn find_transaction(&self, uid: u64) -> Option<(usize, &Transaction)> {
+    self.blocks.iter().enumerate().find_map(|(index, block)| {
+      block.transactions.iter()
+        .find(|transaction| !transaction.is_coinbase() && transaction.uid == uid)
+        .map(|transaction| (index, transaction))
+    })
+  }
+
+  // every transaction but the coinbases, pruned ones included; each has its own uid
+  pub fn transaction_count(&self) -> usize {
+    self.seen_uids.len()
+  }
+
+  // one line per block for reading by eye, unlike save there's no getting the chain back from it
+  pub fn summary(&self) -> String {
+    let mut summary = String::new();
+    for (height, block) in self.blocks.iter().enumerate() {
+      let transactions = if block.is_pruned() { "pruned".to_string() } else { block.transactions.len().to_string() };
+      let miner = Address::from_public_key(&block.miner);
+      summary += &format!("{} {} time {} transactions {} miner {}\n", height, block.hash.short(), block.timestamp, transactions, miner);
+    }
+    summary
+  }
+
+  // every transaction address sent or was paid by, coinbases included, with the height of
+  // its block; pruned blocks have nothing left to show
+  pub fn history(&self, address: &Address) -> Vec<(u64, &Transaction)> {
+    let involves = |key: &RsaPublicKey| Address::from_public_key(key) == *address;
+    self.blocks.iter().enumerate()
+      .flat_map(|(height, block)| block.transactions.iter().map(move |transaction| (height as u64, transaction)))
+      .filter(|(_, transaction)| involves(&transaction.sender) || transaction.outputs().any(|(receiver, _)| involves(receiver)))
+      .collect()
+  }
+
+  // the genesis block is at height 0
+  pub fn block_at(&self, height: u64) -> Option<&Block> {
+    usize::try_from(height).ok().and_then(|height| self.blocks.get(height))
+  }
+
+  // every block at or after height, empty once height is past the tip
+  pub fn blocks_from(&self, height: usize) -> &[Block] {
+    self.blocks.get(height..).unwrap_or(&[])
+  }
+
+  pub fn len(&self) -> usize {
+    self.blocks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.blocks.is_empty()
+  }
+
+  pub fn last_block(&self) -> &Block {
+    self.blocks.last().expect("chain has no genesis block")
+  }
+
+  pub fn last_hash(&self) -> SHA256Hash {
+    self.last_block().hash
+  }
+}
+
+impl Default for Blockchain {
+  fn default() -> Self {
+    Self::new(ChainParams::default())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*; 
+
+  #[test]
+  fn test_transaction_correct() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    let is_good = transaction.verify();
+    assert!(is_good);
+  }
+
+  #[test]
+  fn test_transaction_change_amount() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    transaction.amount = 100 * COIN;
+    let is_good = transaction.verify();
+    assert!(!is_good);
+  }
+
+  #[test]
+  fn test_transaction_change_receiver() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let (_private_key2, public_key3) = generate_key_pair().unwrap();
+    let mut transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    transaction.receiver = public_key3;
+    let is_good = transaction.verify();
+    assert!(!is_good);
+  }
+
+  #[test]
+  fn test_transaction_change_uid() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let (_private_key2, _public_key3) = generate_key_pair().unwrap();
+    let mut transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    transaction.uid = 2;
+    let is_good = transaction.verify();
+    assert!(!is_good);
+  }
+
+  #[test]
+  fn test_format_amount() {
+    assert_eq!(format_amount(0), "0");
+    assert_eq!(format_amount(BLOCK_REWARD), "50");
+    assert_eq!(format_amount(3 * COIN / 2), "1.5");
+    assert_eq!(format_amount(1), "0.00000001");
+    assert_eq!(format_amount(12 * COIN + 345), "12.00000345");
+  }
+
+  #[test]
+  fn test_parse_amount() {
+    assert_eq!(parse_amount("50"), Ok(BLOCK_REWARD));
+    assert_eq!(parse_amount("1.5"), Ok(3 * COIN / 2));
+    assert_eq!(parse_amount("0.00000001"), Ok(1));
+    assert_eq!(parse_amount(&format_amount(12 * COIN + 345)), Ok(12 * COIN + 345));
+    assert_eq!(parse_amount(""), Err(ParseAmountError::Invalid));
+    assert_eq!(parse_amount(".5"), Err(ParseAmountError::Invalid));
+    assert_eq!(parse_amount("-1"), Err(ParseAmountError::Invalid));
+    assert_eq!(parse_amount("1.2.3"), Err(ParseAmountError::Invalid));
+    assert_eq!(parse_amount("0.000000001"), Err(ParseAmountError::TooPrecise));
+    assert_eq!(parse_amount("200000000000"), Err(ParseAmountError::Overflow));
+  }
+
+  #[test]
+  fn test_integer_amounts_sum_exactly() {
+    // a tenth of a coin, ten thousand times
+    let total: u64 = std::iter::repeat_n(COIN / 10, 10_000).sum();
+    assert_eq!(total, 1000 * COIN);
+    // the same sum in f64 picks up rounding error along the way
+    let drifted: f64 = std::iter::repeat_n(0.1_f64, 10_000).sum();
+    assert_ne!(drifted, 1000.0);
+  }
+
+  #[test]
+  fn test_transaction_hash() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    assert_eq!(transaction.hash(), transaction.hash());
+    let same = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    assert_eq!(same.hash(), transaction.hash());
+    let different = Transaction::new(public_key, private_key, public_key2, 11 * COIN, 1);
+    assert_ne!(different.hash(), transaction.hash());
+  }
+
+  #[test]
+  fn test_transaction_clone() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    // clone both before and after the hash is memoized
+    let unhashed = transaction.clone();
+    transaction.hash();
+    let cloned = transaction.clone();
+    assert_eq!(cloned, transaction);
+    assert_eq!(unhashed, transaction);
+    assert!(cloned.verify());
+    let mut changed = transaction.clone();
+    changed.signature[0] ^= 1;
+    assert_ne!(changed, transaction);
+  }
+
+  #[test]
+  fn test_transaction_with_outputs() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let (_, public_key3) = generate_key_pair().unwrap();
+    let (_, public_key4) = generate_key_pair().unwrap();
+    let outputs = vec![(public_key2.clone(), COIN), (public_key3.clone(), 2 * COIN), (public_key4.clone(), 3 * COIN)];
+    let transaction = Transaction::with_outputs(public_key.clone(), private_key.clone(), outputs.clone(), 1);
+    assert!(transaction.verify());
+    assert_eq!(transaction.total_amount(), 6 * COIN);
+    assert_eq!(transaction.outputs().count(), 3);
+
+    let mut tampered = Transaction::with_outputs(public_key.clone(), private_key.clone(), outputs.clone(), 1);
+    tampered.extra_outputs[1].1 = 30 * COIN;
+    assert!(!tampered.verify());
+
+    let mut tampered = Transaction::with_outputs(public_key, private_key, outputs, 1);
+    tampered.extra_outputs[0].0 = public_key2;
+    assert!(!tampered.verify());
+  }
+
+  #[test]
+  fn test_balance_of_credits_every_output() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let (_, public_key3) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let outputs = vec![(public_key2.clone(), 10 * COIN), (public_key3.clone(), 15 * COIN)];
+    let transaction = Transaction::with_outputs(public_key.clone(), private_key.clone(), outputs, 1);
+    chain.add_block(vec![transaction], &private_key).unwrap();
+    assert_eq!(chain.balance_of(&public_key), 75 * COIN);
+    assert_eq!(chain.balance_of(&public_key2), 10 * COIN);
+    assert_eq!(chain.balance_of(&public_key3), 15 * COIN);
+  }
+
+  #[test]
+  fn test_add_block_rejects_outputs_over_balance() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    // each output is affordable on its own but not together
+    let outputs = vec![(public_key2.clone(), 30 * COIN), (public_key2.clone(), 30 * COIN)];
+    let transaction = Transaction::with_outputs(public_key.clone(), private_key.clone(), outputs, 1);
+    assert_eq!(chain.add_block(vec![transaction], &private_key), Err(BlockError::InsufficientFunds { uid: 1 }));
+  }
+
+  #[test]
+  fn test_transaction_change_fee() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut transaction = Transaction::with_fee(public_key, private_key, public_key2, 10 * COIN, COIN, 1);
+    assert!(transaction.verify());
+    transaction.fee = 0;
+    assert!(!transaction.verify());
+  }
+
+  #[test]
+  fn test_block_pays_fees_to_miner() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let (private_key3, public_key3) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let transaction = Transaction::with_fee(public_key.clone(), private_key, public_key2.clone(), 10 * COIN, 5 * COIN / 2, 1);
+    chain.add_block(vec![transaction], &private_key3).unwrap();
+    assert_eq!(chain.balance_of(&public_key), 75 * COIN / 2);
+    assert_eq!(chain.balance_of(&public_key2), 10 * COIN);
+    assert_eq!(chain.balance_of(&public_key3), BLOCK_REWARD + 5 * COIN / 2);
+  }
+
+  #[test]
+  fn test_add_block_rejects_sentinel_transactions() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let burn = Transaction::new(public_key.clone(), private_key.clone(), sentinel_key(), 10 * COIN, 1);
+    assert_eq!(chain.add_block(vec![burn], &private_key), Err(BlockError::PaysSentinel { uid: 1 }));
+    let minted = Transaction::coinbase(public_key.clone(), 10 * COIN, 2);
+    assert_eq!(chain.add_block(vec![minted], &private_key), Err(BlockError::BadCoinbase));
+    assert_eq!(chain.len(), 2);
+  }
+
+  #[test]
+  fn test_zero_amounts_rejected() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let nothing = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 0, 1);
+    assert!(nothing.verify());
+    assert!(!nothing.has_valid_amounts());
+    assert_eq!(chain.add_block(vec![nothing.clone()], &private_key), Err(BlockError::ZeroAmount { uid: 1 }));
+    let outputs = vec![(public_key2.clone(), COIN), (public_key2, 0)];
+    let empty_output = Transaction::with_outputs(public_key.clone(), private_key.clone(), outputs, 2);
+    assert_eq!(chain.add_block(vec![empty_output], &private_key), Err(BlockError::ZeroAmount { uid: 2 }));
+    assert!(Transaction::coinbase(public_key.clone(), 0, 3).has_valid_amounts());
+
+    // a block that slipped one in anyway fails validation
+    let mut template = chain.block_template(Vec::new(), public_key).unwrap();
+    template.transactions.push(nothing);
+    let block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    assert_eq!(chain.append_block(block.clone()), Err(BlockError::ZeroAmount { uid: 1 }));
+    chain.blocks.push(block);
+    assert_eq!(chain.validate(), Err(ValidationError::ZeroAmount { index: 2 }));
+  }
+
+  #[test]
+  fn test_can_accept() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 1);
+    assert_eq!(chain.can_accept(&payment), Ok(()));
+    chain.add_block(vec![payment.clone()], &private_key).unwrap();
+    assert_eq!(chain.can_accept(&payment), Err(RejectReason::DuplicateUid));
+
+    let burn = Transaction::new(public_key.clone(), private_key.clone(), sentinel_key(), COIN, 2);
+    assert_eq!(chain.can_accept(&burn), Err(RejectReason::SentinelKey));
+    assert_eq!(chain.can_accept(&Transaction::coinbase(public_key.clone(), COIN, 3)), Err(RejectReason::SentinelKey));
+    let nothing = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 0, 4);
+    assert_eq!(chain.can_accept(&nothing), Err(RejectReason::ZeroAmount));
+    let late = TransactionBuilder::new().sender(public_key.clone()).receiver(public_key2.clone()).amount(COIN).uid(5).valid_until_height(2).sign_with(private_key.clone()).build().unwrap();
+    assert_eq!(chain.can_accept(&late), Err(RejectReason::Expired));
+    let forged = Transaction::new(public_key.clone(), private_key2.clone(), public_key2.clone(), COIN, 6);
+    assert_eq!(chain.can_accept(&forged), Err(RejectReason::InvalidSignature));
+    let overspend = Transaction::new(public_key2.clone(), private_key2, public_key, 2 * COIN, 7);
+    assert_eq!(chain.can_accept(&overspend), Err(RejectReason::InsufficientFunds));
+  }
+
+  #[test]
+  fn test_add_block_counts_fee_against_balance() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let transaction = Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2, 50 * COIN, COIN / 2, 1);
+    assert_eq!(chain.add_block(vec![transaction], &private_key), Err(BlockError::InsufficientFunds { uid: 1 }));
+  }
+
+  #[test]
+  fn test_transaction_builder() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let built = TransactionBuilder::new()
+      .sender(public_key.clone())
+      .receiver(public_key2.clone())
+      .amount(10 * COIN)
+      .uid(1)
+      .sign_with(private_key.clone())
+      .build()
+      .unwrap();
+    assert_eq!(built, Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1));
+    assert!(built.verify());
+
+    let with_fee = TransactionBuilder::new()
+      .fee(COIN)
+      .uid(2)
+      .amount(10 * COIN)
+      .receiver(public_key2.clone())
+      .sender(public_key.clone())
+      .sign_with(private_key.clone())
+      .build()
+      .unwrap();
+    assert_eq!(with_fee, Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, COIN, 2));
+
+    let no_amount = TransactionBuilder::new().sender(public_key.clone()).receiver(public_key2.clone()).uid(1).sign_with(private_key);
+    assert_eq!(no_amount.build(), Err(BuildError::MissingAmount));
+    let unsigned = TransactionBuilder::new().sender(public_key).receiver(public_key2).amount(COIN).uid(1);
+    assert_eq!(unsigned.build(), Err(BuildError::MissingSigningKey));
+  }
+
+  #[test]
+  fn test_transaction_expires_after_valid_until_height() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let build = |uid, height| TransactionBuilder::new()
+      .sender(public_key.clone())
+      .receiver(public_key2.clone())
+      .amount(COIN)
+      .uid(uid)
+      .valid_until_height(height)
+      .sign_with(private_key.clone())
+      .build()
+      .unwrap();
+    let expiring = build(1, 2);
+    assert!(expiring.verify());
+    assert_ne!(expiring.signing_bytes(), Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 1).signing_bytes());
+    // the expiry is signed, so it can't be lifted afterwards
+    let mut lifted = expiring.clone();
+    lifted.valid_until_height = None;
+    assert!(!lifted.verify());
+
+    chain.add_block(vec![expiring], &private_key).unwrap();
+    assert_eq!(chain.add_block(vec![build(2, 2)], &private_key), Err(BlockError::Expired { uid: 2 }));
+    let unlimited = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 3);
+    assert_eq!(unlimited.valid_until_height(), None);
+    chain.add_block(vec![unlimited], &private_key).unwrap();
+
+    // a block from a miner that ignored the expiry is turned away as well
+    let mut template = chain.block_template(Vec::new(), public_key.clone()).unwrap();
+    template.transactions.push(build(4, 3));
+    let late = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    assert_eq!(chain.append_block(late.clone()), Err(BlockError::Expired { uid: 4 }));
+    chain.blocks.push(late);
+    assert_eq!(chain.validate(), Err(ValidationError::ExpiredTransaction { index: 4 }));
+    chain.blocks.pop();
+    assert!(build(5, 4).is_valid_at(4));
+  }
+
+  #[test]
+  fn test_transaction_display() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let (_, public_key3) = generate_key_pair().unwrap();
+    let sender = Address::from_public_key(&public_key).short();
+    let receiver = Address::from_public_key(&public_key2).short();
+    let transaction = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 3 * COIN / 2, 7);
+    assert_eq!(transaction.to_string(), format!("{} -> {} 1.5 (uid 7, verified)", sender, receiver));
+
+    let outputs = vec![(public_key2, COIN), (public_key3.clone(), 2 * COIN)];
+    let mut transaction = Transaction::with_outputs(public_key.clone(), private_key, outputs, 8);
+    transaction.fee = COIN / 10;
+    let other = Address::from_public_key(&public_key3).short();
+    assert_eq!(transaction.to_string(), format!("{} -> {} 1, {} 2 fee 0.1 (uid 8, unverified)", sender, receiver, other));
+
+    let coinbase = Transaction::coinbase(public_key.clone(), BLOCK_REWARD, 1);
+    assert_eq!(coinbase.to_string(), format!("coinbase -> {} 50 (uid 1, verified)", sender));
+  }
+
+  #[test]
+  fn test_hashes_equal() {
+    let hash = SHA256Hash::from([7_u8; 32]);
+    assert!(hashes_equal(&hash, &SHA256Hash::from([7_u8; 32])));
+    let mut bytes = [7_u8; 32];
+    bytes[31] = 8;
+    assert!(!hashes_equal(&hash, &SHA256Hash::from(bytes)));
+    assert!(!hashes_equal(&hash, &ZERO_HASH));
+  }
+
+  #[test]
+  fn test_address_from_public_key() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    assert_eq!(Address::from_public_key(&public_key), Address::from_public_key(&public_key));
+    assert_ne!(Address::from_public_key(&public_key), Address::from_public_key(&public_key2));
+    let display = Address::from_public_key(&public_key).to_string();
+    assert_eq!(display.len(), 64);
+    assert!(display.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(display.parse(), Ok(Address::from_public_key(&public_key)));
+    assert!("not an address".parse::<Address>().is_err());
+  }
+
+  #[test]
+  fn test_balance_of_address() {
+    let chain = build_validation_chain();
+    let miner = chain.last_block().miner.clone();
+    assert_eq!(chain.balance_of_address(&Address::from_public_key(&miner)), chain.balance_of(&miner));
+    assert_eq!(chain.balance_of_address(&Address::from_public_key(&sentinel_key())), 0);
+  }
+
+  #[test]
+  fn test_signing_bytes_deterministic() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let first = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    let second = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    assert_eq!(first.signing_bytes(), second.signing_bytes());
+    let third = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 2);
+    assert_ne!(first.signing_bytes(), third.signing_bytes());
+  }
+
+  #[test]
+  fn test_transaction_json_round_trip() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    let json = serde_json::to_string(&transaction).unwrap();
+    let decoded: Transaction = serde_json::from_str(&json).unwrap();
+    assert!(decoded.verify());
+    assert_eq!(decoded.sender, transaction.sender);
+    assert_eq!(decoded.receiver, transaction.receiver);
+    assert_eq!(decoded.signature, transaction.signature);
+  }
+
+  #[test]
+  fn test_transaction_bytes_round_trip() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::with_fee(public_key, private_key, public_key2, 10 * COIN, COIN, 1);
+    let bytes = transaction.to_bytes();
+    assert!(bytes.len() < serde_json::to_vec(&transaction).unwrap().len());
+    let decoded = Transaction::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded, transaction);
+    assert!(decoded.verify());
+    assert_eq!(decoded.hash(), transaction.hash());
+    assert!(Transaction::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+  }
+
+  #[test]
+  fn test_block_bytes_round_trip() {
+    let chain = build_validation_chain();
+    let block = chain.last_block();
+    let bytes = block.to_bytes();
+    assert!(bytes.len() < serde_json::to_vec(block).unwrap().len());
+    let decoded = Block::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.hash, block.hash);
+    assert_eq!(decoded.previous_hash, block.previous_hash);
+    assert_eq!(decoded.transactions, block.transactions);
+    assert!(decoded.verify_pow());
+    assert!(decoded.transactions.iter().all(Transaction::verify));
+  }
+
+  #[test]
+  fn test_transaction_loads_old_receiver_spelling() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+    let json = serde_json::to_string(&transaction).unwrap();
+    assert!(json.contains("\"receiver\""));
+    let old = json.replace("\"receiver\"", "\"reciver\"");
+    let decoded: Transaction = serde_json::from_str(&old).unwrap();
+    assert_eq!(decoded, transaction);
+    assert!(decoded.verify());
+  }
+
+  #[test]
+  fn test_block_links_previous_hash() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut transactions = vec![Transaction::new(public_key.clone(), private_key, public_key2, 10 * COIN, 1)];
+    let target = target_from_leading_zero_bits(0);
+    let miner = public_key_der(&public_key);
+    let (_, first_hash) = Block::check_block(&merkle_root(&transactions), &miner, 0, &ZERO_HASH, 0, &target);
+    let (_, second_hash) = Block::check_block(&merkle_root(&[]), &miner, 0, &first_hash, 0, &target);
+    transactions[0].amount = 100 * COIN;
+    // tampering goes behind the memoized hash's back, so drop it
+    transactions[0].hash_cache = HashCache::default();
+    let (_, tampered_first_hash) = Block::check_block(&merkle_root(&transactions), &miner, 0, &ZERO_HASH, 0, &target);
+    let (_, rehashed_second) = Block::check_block(&merkle_root(&[]), &miner, 0, &tampered_first_hash, 0, &target);
+    assert_ne!(tampered_first_hash, first_hash);
+    assert_ne!(rehashed_second, second_hash);
+  }
+
+  #[test]
+  fn test_blockchain_three_blocks() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    assert_eq!(chain.len(), 1);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(chain.len(), 2);
+    let transaction = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, 1);
+    chain.add_block(vec![transaction], &private_key).unwrap();
+    assert_eq!(chain.len(), 3);
+    assert_eq!(chain.blocks[0].previous_hash, ZERO_HASH);
+    for i in 1..chain.len() {
+      assert_eq!(chain.blocks[i].previous_hash, chain.blocks[i - 1].hash);
+    }
+    assert_eq!(chain.last_hash(), chain.blocks[2].hash);
+  }
+
+  #[test]
+  fn test_block_timestamps_increase() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert!(chain.blocks[1].timestamp() > 0);
+    assert!(chain.blocks[2].timestamp() >= chain.blocks[1].timestamp());
+  }
+
+  #[test]
+  fn test_next_difficulty_raised_for_fast_blocks() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(4);
+    for _ in 0..RETARGET_INTERVAL {
+      chain.add_block(Vec::new(), &private_key).unwrap();
+    }
+    for (i, block) in chain.blocks.iter_mut().enumerate() {
+      block.timestamp = 1000 + i as u64;
+    }
+    assert_eq!(chain.next_difficulty(), 5);
+  }
+
+  #[test]
+  fn test_next_difficulty_lowered_for_slow_blocks() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(4);
+    for _ in 0..RETARGET_INTERVAL {
+      chain.add_block(Vec::new(), &private_key).unwrap();
+    }
+    for (i, block) in chain.blocks.iter_mut().enumerate() {
+      block.timestamp = 1000 + i as u64 * 2 * TARGET_BLOCK_SECONDS;
+    }
+    assert_eq!(chain.next_difficulty(), 3);
+  }
+
+  #[test]
+  fn test_next_difficulty_unchanged_between_retargets() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(4);
+    assert_eq!(chain.next_difficulty(), 4);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(chain.next_difficulty(), 4);
+  }
+
+  #[test]
+  fn test_merkle_root_order_matters() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let first = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    let second = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 20 * COIN, 2);
+    let third = Transaction::new(public_key, private_key, public_key2, 30 * COIN, 3);
+    let expected = hash_pair(&hash_pair(&first.hash(), &second.hash()), &hash_pair(&third.hash(), &third.hash()));
+    let mut transactions = vec![first, second, third];
+    let root = merkle_root(&transactions);
+    assert_eq!(root, expected);
+    transactions.swap(0, 1);
+    assert_ne!(merkle_root(&transactions), root);
+  }
+
+  #[test]
+  fn test_merkle_proof() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transactions: Vec<Transaction> = (0..5)
+      .map(|uid| Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, uid))
+      .collect();
+    let root = merkle_root(&transactions);
+    for index in 0..transactions.len() {
+      let proof = merkle_proof(&transactions, index);
+      assert!(verify_merkle_proof(&transactions[index].hash(), index, &proof, &root));
+    }
+    let mut proof = merkle_proof(&transactions, 2);
+    assert!(!verify_merkle_proof(&transactions[2].hash(), 3, &proof, &root));
+    let mut flipped = *proof[0].as_bytes();
+    flipped[0] ^= 1;
+    proof[0] = SHA256Hash::from(flipped);
+    assert!(!verify_merkle_proof(&transactions[2].hash(), 2, &proof, &root));
+  }
+
+  #[test]
+  fn test_merkle_root_empty() {
+    assert_eq!(merkle_root(&[]), ZERO_HASH);
+    assert_eq!(merkle_root(&[]), merkle_root(&[]));
+  }
+
+  #[test]
+  fn test_block_pays_miner_coinbase() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let transaction = Transaction::new(public_key, private_key, public_key2.clone(), 10 * COIN, 1);
+    chain.add_block(vec![transaction], &private_key2).unwrap();
+    let transactions = chain.last_block().transactions();
+    let coinbases: Vec<&Transaction> = transactions.iter().filter(|transaction| transaction.is_coinbase()).collect();
+    assert_eq!(coinbases.len(), 1);
+    assert!(transactions[0].is_coinbase());
+    assert_eq!(coinbases[0].receiver, public_key2);
+    assert_eq!(coinbases[0].amount, BLOCK_REWARD);
+    assert!(coinbases[0].verify());
+    assert_eq!(chain.validate(), Ok(()));
+  }
+
+  #[test]
+  fn test_coinbase_must_pay_miner() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let mut template = chain.block_template(Vec::new(), public_key).unwrap();
+    template.transactions[0] = Transaction::coinbase(public_key2, BLOCK_REWARD, 1);
+    let block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    assert_eq!(chain.append_block(block.clone()), Err(BlockError::BadCoinbase));
+    chain.blocks.push(block);
+    assert_eq!(chain.validate(), Err(ValidationError::CoinbaseNotToMiner { index: 1 }));
+  }
+
+  #[test]
+  fn test_block_must_be_signed_by_miner() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, _public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let template = chain.block_template(Vec::new(), public_key).unwrap();
+    let mut block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    let bad_signature = Err(BlockError::Invalid(ValidationError::BadBlockSignature { index: 1 }));
+    assert_eq!(chain.append_block(block.clone()), bad_signature);
+    block.sign(&private_key2);
+    assert_eq!(chain.append_block(block.clone()), bad_signature);
+    assert_eq!(chain.len(), 1);
+    block.sign(&private_key);
+    assert_eq!(chain.append_block(block), Ok(()));
+    assert_eq!(chain.validate(), Ok(()));
+  }
+
+  #[test]
+  fn test_coinbase_with_signature_fails() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let mut coinbase = Transaction::coinbase(public_key, BLOCK_REWARD, 1);
+    coinbase.signature = vec![1, 2, 3];
+    assert!(!coinbase.verify());
+  }
+
+  #[test]
+  fn test_balance_of() {
+    let (miner_private_key, miner) = generate_key_pair().unwrap();
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &miner_private_key).unwrap();
+    let payment = Transaction::new(miner.clone(), miner_private_key.clone(), public_key.clone(), 30 * COIN, 1);
+    chain.add_block(vec![payment], &miner_private_key).unwrap();
+    let payment = Transaction::new(public_key.clone(), private_key, public_key2.clone(), 25 * COIN / 2, 2);
+    chain.add_block(vec![payment], &miner_private_key).unwrap();
+    assert_eq!(chain.balance_of(&miner), 3 * BLOCK_REWARD - 30 * COIN);
+    assert_eq!(chain.balance_of(&public_key), 35 * COIN / 2);
+    assert_eq!(chain.balance_of(&public_key2), 25 * COIN / 2);
+  }
+
+  #[test]
+  fn test_total_supply() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    assert_eq!(chain.total_supply(), 0);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let payment = Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, COIN, 1);
+    chain.add_block(vec![payment], &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let expected: u64 = (1..chain.len() as u64).map(block_reward).sum();
+    assert_eq!(chain.total_supply(), expected);
+  }
+
+  #[test]
+  fn test_balance_of_ignores_tampering() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    chain.add_block(vec![payment], &private_key).unwrap();
+    // balances only ever change with an accepted block, and a tampered one never loads
+    chain.blocks[2].transactions[1].amount = 20 * COIN;
+    assert_eq!(chain.balance_of(&public_key), 2 * BLOCK_REWARD - 10 * COIN);
+    assert_eq!(chain.balance_of(&public_key2), 10 * COIN);
+    assert_eq!(chain.replayed().err(), Some(ValidationError::InvalidTransaction { index: 2 }));
+  }
+
+  #[test]
+  fn test_balances_follow_blocks_and_reorgs() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key2).unwrap();
+    let fork_point = chain.truncated(chain.len());
+    let payment = Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, COIN, 1);
+    let refund = Transaction::with_outputs(public_key2.clone(), private_key2.clone(), vec![(public_key.clone(), COIN), (public_key2.clone(), COIN)], 2);
+    chain.add_block(vec![payment], &private_key).unwrap();
+    chain.add_block(vec![refund], &private_key2).unwrap();
+    assert_eq!(chain.balances, chain.replayed().unwrap().balances);
+    assert_eq!(chain.balance_of(&public_key), 2 * BLOCK_REWARD - 10 * COIN + COIN);
+
+    // a longer branch without either transaction undoes both
+    let mut branch = fork_point;
+    let other = Transaction::new(public_key.clone(), private_key, public_key2.clone(), 3 * COIN, 3);
+    branch.add_block(vec![other], &private_key2).unwrap();
+    for _ in 0..2 {
+      branch.add_block(Vec::new(), &private_key2).unwrap();
+    }
+    chain.reorg_to(branch.blocks[3..].to_vec()).unwrap();
+    assert_eq!(chain.balances, chain.replayed().unwrap().balances);
+    assert_eq!(chain.balance_of(&public_key), BLOCK_REWARD - 3 * COIN);
+    assert_eq!(chain.balance_of(&public_key2), 4 * BLOCK_REWARD + 3 * COIN);
+    assert_eq!(chain.truncated(3).balances, chain.truncated(3).replayed().unwrap().balances);
+  }
+
+  #[test]
+  fn test_add_block_rejects_overspend() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let transaction = Transaction::new(public_key.clone(), private_key, public_key2.clone(), 10 * COIN, 1);
+    assert_eq!(chain.add_block(vec![transaction], &private_key2), Err(BlockError::InsufficientFunds { uid: 1 }));
+    assert_eq!(chain.len(), 1);
+  }
+
+  #[test]
+  fn test_add_block_counts_earlier_spends_in_block() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let first = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 30 * COIN, 1);
+    let second = Transaction::new(public_key.clone(), private_key, public_key2.clone(), 30 * COIN, 2);
+    assert_eq!(chain.add_block(vec![first, second], &private_key2), Err(BlockError::InsufficientFunds { uid: 2 }));
+  }
+
+  #[test]
+  fn test_add_block_rejects_replayed_transaction() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let transaction = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    chain.add_block(vec![transaction], &private_key).unwrap();
+    let replay = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, 1);
+    assert_eq!(chain.add_block(vec![replay], &private_key), Err(BlockError::DuplicateUid { uid: 1 }));
+    assert_eq!(chain.len(), 3);
+  }
+
+  #[test]
+  fn test_add_block_rejects_duplicate_uid_in_block() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let first = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    let second = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, 1);
+    assert_eq!(chain.add_block(vec![first, second], &private_key), Err(BlockError::DuplicateUid { uid: 1 }));
+  }
+
+  fn build_validation_chain() -> Blockchain {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let transaction = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, 1);
+    chain.add_block(vec![transaction], &private_key).unwrap();
+    chain
+  }
+
+  // records what the current thread logs, since tests run side by side and the logger is global
+  struct CaptureLogger;
+
+  thread_local! {
+    static CAPTURED: std::cell::RefCell<Vec<(log::Level, String)>> = const { std::cell::RefCell::new(Vec::new()) };
+  }
+
+  impl log::Log for CaptureLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+      true
+    }
+
+    fn log(&self, record: &log::Record) {
+      CAPTURED.with(|captured| captured.borrow_mut().push((record.level(), record.args().to_string())));
+    }
+
+    fn flush(&self) {}
+  }
+
+  fn capture_logs(f: impl FnOnce()) -> Vec<(log::Level, String)> {
+    static LOGGER: CaptureLogger = CaptureLogger;
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+      log::set_logger(&LOGGER).unwrap();
+      log::set_max_level(log::LevelFilter::Trace);
+    });
+    CAPTURED.with(|captured| captured.borrow_mut().clear());
+    f();
+    CAPTURED.with(|captured| captured.take())
+  }
+
+  #[test]
+  fn test_mining_logs_block_mined() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let records = capture_logs(|| chain.add_block(Vec::new(), &private_key).unwrap());
+    let nonce = chain.last_block().nonce();
+    assert!(records.contains(&(log::Level::Info, format!("block mined at height 1 with nonce {}", nonce))));
+  }
+
+  fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("shitcoin-{}-{}.json", std::process::id(), name))
+  }
+
+  #[test]
+  fn test_save_and_load() {
+    let chain = build_validation_chain();
+    let path = temp_path("chain");
+    chain.save(&path).unwrap();
+    let loaded = Blockchain::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.len(), chain.len());
+    assert_eq!(loaded.last_hash(), chain.last_hash());
+    assert_eq!(serde_json::to_string(&loaded).unwrap(), serde_json::to_string(&chain).unwrap());
+    assert_eq!(loaded.seen_uids, chain.seen_uids);
+    assert_eq!(loaded.balances, chain.balances);
+    assert_eq!(loaded.validate(), Ok(()));
+  }
+
+  #[test]
+  fn test_load_rejects_tampered_file() {
+    let chain = build_validation_chain();
+    let mut json = serde_json::to_value(&chain).unwrap();
+    json["blocks"][2]["transactions"][1]["amount"] = serde_json::json!(1000 * COIN);
+    let path = temp_path("tampered");
+    std::fs::write(&path, json.to_string()).unwrap();
+    let result = Blockchain::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(LoadError::Invalid(ValidationError::InvalidTransaction { index: 2 }))));
+  }
+
+  #[test]
+  fn test_load_rejects_overspend() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    // properly signed and mined, it just spends coins the sender never had
+    let overspend = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 1000 * COIN, 1);
+    let mut template = chain.block_template(Vec::new(), public_key).unwrap();
+    template.transactions.push(overspend);
+    let mut block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    block.sign(&private_key);
+    chain.blocks.push(block);
+    let path = temp_path("overspend");
+    chain.save(&path).unwrap();
+    let result = Blockchain::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(LoadError::Invalid(ValidationError::Overspend { index: 2 }))));
+  }
 
-pub fn verify_message(message: String, signature: &Vec<u8>, public_key: &RsaPublicKey) -> bool {
-  let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
-  let mut hasher = Sha256::new();
-  hasher.update(message);
-  let hash = hasher.finalize();
-  match public_key.verify(padding, &hash, &signature[..]) {
-    Ok(_) => true,
-    Err(_) => false,
+  #[test]
+  fn test_load_rejects_tampered_snapshot() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let attacker = Address::from_public_key(&public_key);
+    let load_tampered = |name: &str, tamper: &dyn Fn(&mut Blockchain)| {
+      let mut chain = build_validation_chain();
+      chain.add_block(Vec::new(), &private_key).unwrap();
+      tamper(&mut chain);
+      let path = temp_path(name);
+      chain.save(&path).unwrap();
+      let result = Blockchain::load(&path);
+      std::fs::remove_file(&path).unwrap();
+      match result {
+        Ok(_) => None,
+        Err(LoadError::Invalid(error)) => Some(error),
+        Err(error) => panic!("{}", error),
+      }
+    };
+    let bad_snapshot = Some(ValidationError::BadSnapshot);
+
+    assert_eq!(load_tampered("snapshot-untouched", &|chain| chain.prune_before(2)), None);
+    // coins out of nowhere with nothing pruned
+    assert_eq!(load_tampered("snapshot-unpruned", &|chain| {
+      chain.snapshot.balances.insert(attacker, 1000 * COIN);
+    }), bad_snapshot);
+    // claiming blocks that still have their transactions, so they'd skip the spend checks
+    assert_eq!(load_tampered("snapshot-too-high", &|chain| {
+      chain.prune_before(2);
+      chain.snapshot.height = 3;
+    }), bad_snapshot);
+    assert_eq!(load_tampered("snapshot-too-low", &|chain| {
+      chain.prune_before(3);
+      chain.snapshot.height = 2;
+    }), bad_snapshot);
+    assert_eq!(load_tampered("snapshot-supply", &|chain| {
+      chain.prune_before(3);
+      chain.snapshot.supply += COIN;
+    }), bad_snapshot);
+    assert_eq!(load_tampered("snapshot-balances", &|chain| {
+      chain.prune_before(3);
+      chain.snapshot.balances.insert(attacker, chain.snapshot.supply);
+    }), bad_snapshot);
   }
-}
 
-pub type SHA256Hash = [u8; 32];
+  #[test]
+  fn test_load_rejects_corrupt_file() {
+    let path = temp_path("corrupt");
+    std::fs::write(&path, "not a chain").unwrap();
+    let result = Blockchain::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(LoadError::Parse(_))));
+  }
 
-#[derive(Debug)]
-pub struct Transaction {
-  sender: RsaPublicKey,
-  reciver: RsaPublicKey,
-  amount: f64,
-  uid: u64,
-  signature: Vec<u8>,
-}
+  // mine a block on a copy of chain and hand it back for append_block
+  fn mine_on_copy(chain: &Blockchain, transactions: Vec<Transaction>, miner: &RsaPrivateKey) -> Block {
+    let json = serde_json::to_string(chain).unwrap();
+    let mut copy: Blockchain = serde_json::from_str(&json).unwrap();
+    copy.seen_uids = chain.seen_uids.clone();
+    copy.balances = chain.balances.clone();
+    copy.add_block(transactions, miner).unwrap();
+    copy.blocks.pop().unwrap()
+  }
 
-impl Transaction {
-  pub fn new(sender: RsaPublicKey, sender_private_key: RsaPrivateKey, reciver: RsaPublicKey, amount: f64, uid: u64) -> Self {
-    let transaction_string = format!("{:?} {:?} {:?} {:?}", sender, reciver, amount, uid);
-    //println!("{}", transaction_string);
-    let signature = sign_message(transaction_string, &sender_private_key);
-    //println!("{:?}", signature);
-    Transaction { sender, reciver, amount, uid, signature }
+  #[test]
+  fn test_transaction_count() {
+    let mut chain = build_validation_chain();
+    assert_eq!(chain.transaction_count(), 1);
+    let tip = chain.len() as u64;
+    chain.prune_before(tip);
+    assert_eq!(chain.transaction_count(), 1);
   }
 
-  pub fn verify(&self) -> bool {
-    let transaction_string = format!("{:?} {:?} {:?} {:?}", self.sender, self.reciver, self.amount, self.uid);
-    verify_message(transaction_string, &self.signature, &self.sender)
+  #[test]
+  fn test_iterators() {
+    let chain = build_validation_chain();
+    assert_eq!(chain.blocks().count(), chain.len());
+    let manual: usize = (0..chain.len()).map(|index| chain.blocks[index].transactions.len()).sum();
+    assert_eq!(chain.transactions().count(), manual);
+    assert_eq!(manual, 3);
+    assert_eq!(chain.transactions().filter(|transaction| transaction.is_coinbase()).count(), 2);
   }
-}
 
-struct Block {
-  transactions: Vec<Transaction>,
-  nonce: u64,
-  miner: RsaPublicKey,
-  hash: SHA256Hash,
-}
+  #[test]
+  fn test_prune_before() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = build_validation_chain();
+    let miner = chain.last_block().miner.clone();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let tip = chain.last_hash();
+    let balances = [chain.balance_of(&miner), chain.balance_of(&public_key)];
+    let supply = chain.total_supply();
 
-impl Block {
+    chain.prune_before(3);
+    assert!(chain.blocks[..3].iter().all(Block::is_pruned));
+    assert!(chain.blocks[2].transactions.is_empty());
+    assert!(!chain.blocks[3].is_pruned());
+    assert_eq!(chain.last_hash(), tip);
+    assert_eq!([chain.balance_of(&miner), chain.balance_of(&public_key)], balances);
+    assert_eq!(chain.total_supply(), supply);
 
-  #[inline(always)]
-  fn check_block(transactions: &Vec<Transaction>, miner: &RsaPublicKey, nonce: u64) -> (bool, SHA256Hash) {
-    let mut hasher = Sha256::new();
-    let block_string = format!("{:?} {:?} {:?}", transactions, miner, nonce);
-    hasher.update(block_string.as_bytes());
-    let hash = hasher.finalize();
-    let mut is_zeros = true;
-    for i in 0..4 {
-      if hash[i] != 0 {
-        is_zeros = false;
-        break;
-      }
+    // the pruned headers still validate once the chain comes back from disk
+    let path = temp_path("pruned");
+    chain.save(&path).unwrap();
+    let mut loaded = Blockchain::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.validate(), Ok(()));
+    assert_eq!(loaded.balance_of(&miner), balances[0]);
+    assert!(loaded.seen_uids.contains(&1));
+
+    // spending still counts the snapshot, and pruning again only touches new blocks
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 2);
+    loaded.add_block(vec![payment], &private_key).unwrap();
+    loaded.prune_before(u64::MAX);
+    assert_eq!(loaded.balance_of(&public_key), 2 * BLOCK_REWARD - 10 * COIN);
+    assert_eq!(loaded.balance_of(&public_key2), 10 * COIN);
+    assert_eq!(loaded.total_supply(), supply + BLOCK_REWARD);
+    loaded.validated.store(0, Ordering::SeqCst);
+    assert_eq!(loaded.validate(), Ok(()));
+  }
+
+  #[test]
+  fn test_pruned_block_tampering() {
+    let mut chain = build_validation_chain();
+    chain.prune_before(3);
+    chain.blocks[2].pruned_merkle_root = Some(ZERO_HASH);
+    assert_eq!(chain.validate(), Err(ValidationError::HashMismatch { index: 2 }));
+  }
+
+  #[test]
+  fn test_summary() {
+    let mut chain = build_validation_chain();
+    let summary = chain.summary();
+    assert_eq!(summary.lines().count(), 3);
+    let tip = summary.lines().last().unwrap();
+    assert!(tip.starts_with(&format!("2 {} ", chain.last_hash().short())));
+    assert!(tip.contains("transactions 2"));
+    assert!(tip.ends_with(&Address::from_public_key(&chain.last_block().miner).to_string()));
+    chain.prune_before(2);
+    assert!(chain.summary().lines().nth(1).unwrap().contains("transactions pruned"));
+  }
+
+  #[test]
+  fn test_history() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let (_private_key3, public_key3) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key2).unwrap();
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 1);
+    // only an extra output of this one goes to public_key3
+    let split = Transaction::with_outputs(public_key.clone(), private_key, vec![(public_key2.clone(), COIN), (public_key3.clone(), COIN)], 2);
+    chain.add_block(vec![payment.clone(), split.clone()], &private_key2).unwrap();
+
+    let history = chain.history(&Address::from_public_key(&public_key));
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[0].0, 1);
+    assert!(history[0].1.is_coinbase());
+    assert_eq!(history[1], (3, &payment));
+    assert_eq!(history[2], (3, &split));
+    assert_eq!(chain.history(&Address::from_public_key(&public_key2)).len(), 4);
+    assert_eq!(chain.history(&Address::from_public_key(&public_key3)), vec![(3, &split)]);
+  }
+
+  #[test]
+  fn test_block_at() {
+    let chain = build_validation_chain();
+    assert_eq!(chain.block_at(0).unwrap().hash, chain.genesis_hash());
+    assert_eq!(chain.block_at(2).unwrap().hash, chain.last_hash());
+    assert!(chain.block_at(3).is_none());
+    assert!(chain.block_at(u64::MAX).is_none());
+  }
+
+  #[test]
+  fn test_find_transaction() {
+    let chain = build_validation_chain();
+    let (index, transaction) = chain.find_transaction(1).unwrap();
+    assert_eq!(index, 2);
+    assert_eq!(transaction, &chain.blocks[2].transactions[1]);
+    assert!(chain.find_transaction(2).is_none());
+  }
+
+  #[test]
+  fn test_find_transaction_returns_earliest() {
+    let mut chain = build_validation_chain();
+    let duplicate = chain.blocks[2].transactions[1].clone();
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    // add_block refuses duplicate uids, so sneak it in directly
+    chain.blocks[3].transactions.push(duplicate);
+    assert_eq!(chain.find_transaction(1).unwrap().0, 2);
+  }
+
+  #[test]
+  fn test_blocks_from() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(chain.blocks_from(0).len(), 3);
+    assert_eq!(chain.blocks_from(2)[0].hash, chain.last_hash());
+    assert!(chain.blocks_from(3).is_empty());
+    assert!(chain.blocks_from(10).is_empty());
+  }
+
+  #[test]
+  fn test_append_block() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let block = mine_on_copy(&chain, Vec::new(), &private_key);
+    let hash = block.hash;
+    assert_eq!(chain.append_block(block), Ok(()));
+    assert_eq!(chain.last_hash(), hash);
+    let transaction = Transaction::new(public_key.clone(), private_key, public_key2.clone(), 10 * COIN, 1);
+    let block = mine_on_copy(&chain, vec![transaction], &private_key2);
+    assert_eq!(chain.append_block(block), Ok(()));
+    assert_eq!(chain.balance_of(&public_key2), 60 * COIN);
+    assert!(chain.seen_uids.contains(&1));
+    assert_eq!(chain.validate(), Ok(()));
+  }
+
+  // a properly mined block at the tip of chain whose coinbase pays amount
+  fn mine_with_coinbase(chain: &Blockchain, miner_key: &RsaPrivateKey, amount: u64) -> Block {
+    mine_at(chain, miner_key, amount, unix_time())
+  }
+
+  fn mine_at(chain: &Blockchain, miner_key: &RsaPrivateKey, amount: u64, timestamp: u64) -> Block {
+    let miner = &RsaPublicKey::from(miner_key);
+    let transactions = vec![Transaction::coinbase(miner.clone(), amount, chain.len() as u64)];
+    let previous_hash = chain.last_hash();
+    let difficulty = chain.next_difficulty();
+    let (nonce, hash) = Block::mine_block(&transactions, miner, &previous_hash, timestamp, difficulty).unwrap();
+    let mut block = Block { transactions, nonce, miner: miner.clone(), previous_hash, timestamp, difficulty, hash, pruned_merkle_root: None, signature: Vec::new() };
+    block.sign(miner_key);
+    block
+  }
+
+  #[test]
+  fn test_verify_all_finds_one_tampered_transaction() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let transactions: Vec<Transaction> = (1..=64)
+      .map(|uid| Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN / 2, uid))
+      .collect();
+    assert!(verify_all(&transactions));
+    chain.add_block(transactions, &private_key).unwrap();
+    assert_eq!(chain.validate(), Ok(()));
+
+    let mut tampered = chain.blocks[2].transactions.clone();
+    tampered[40].amount = 10 * COIN;
+    assert_eq!(verify_all(&tampered), tampered.iter().all(Transaction::verify));
+    assert!(!verify_all(&tampered));
+    chain.blocks[2].transactions = tampered;
+    chain.validated.store(0, Ordering::SeqCst);
+    assert_eq!(chain.validate(), Err(ValidationError::InvalidTransaction { index: 2 }));
+  }
+
+  #[test]
+  fn test_block_template() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, 1);
+    let template = chain.block_template(vec![payment.clone()], public_key.clone()).unwrap();
+    assert_eq!(template.transactions(), &[payment.clone()][..]);
+    let mut block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    block.sign(&private_key);
+    assert_eq!(chain.append_block(block), Ok(()));
+    assert_eq!(chain.block_template(vec![payment], public_key.clone()).err(), Some(BlockError::DuplicateUid { uid: 1 }));
+
+    let template = chain.block_template(Vec::new(), public_key).unwrap();
+    assert!(template.mine(&AtomicBool::new(true)).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_append_block_checks_timestamp() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    for timestamp in [100, 200, 300] {
+      chain.add_block_at(Vec::new(), &private_key, timestamp).unwrap();
     }
-    (is_zeros, SHA256Hash::from(hash))
+    // the median of 0, 100, 200 and 300 is 200
+    let past = mine_at(&chain, &private_key, block_reward(4), 200);
+    assert_eq!(chain.append_block(past), Err(BlockError::Invalid(ValidationError::TimestampTooEarly { index: 4 })));
+    let future = mine_at(&chain, &private_key, block_reward(4), unix_time() + MAX_FUTURE_BLOCK_SECONDS + 60);
+    assert_eq!(chain.append_block(future), Err(BlockError::Invalid(ValidationError::TimestampTooFarAhead { index: 4 })));
+    let just_after = mine_at(&chain, &private_key, block_reward(4), 201);
+    assert_eq!(chain.append_block(just_after), Ok(()));
+
+    // a block that went behind the check's back is caught when the chain is validated
+    let past = mine_at(&chain, &private_key, block_reward(5), 150);
+    chain.blocks.push(past);
+    assert_eq!(chain.validate(), Err(ValidationError::TimestampTooEarly { index: 5 }));
   }
 
-  fn mine_block(transactions: Vec<Transaction>, miner: RsaPublicKey) -> SHA256Hash {
-    for i in 0 as u64.. {
-      let (is_correct, hash) = Self::check_block(&transactions, &miner, i);
-      if is_correct {
-        return hash;
-      }
+  #[test]
+  fn test_add_block_moves_timestamp_past_median() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    for _ in 0..3 {
+      chain.add_block_at(Vec::new(), &private_key, 500).unwrap();
     }
-    panic!("Couldn't find a nonce to mine block");
+    let timestamps: Vec<u64> = chain.blocks().map(Block::timestamp).collect();
+    assert_eq!(timestamps, vec![0, 500, 501, 501]);
+    assert_eq!(chain.validate(), Ok(()));
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::*; 
+  #[test]
+  fn test_add_block_rejects_too_many_transactions() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    // the count is checked before anything else, so copies of one transaction will do
+    let transaction = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 0, 1);
+    let transactions = vec![transaction; MAX_TRANSACTIONS_PER_BLOCK + 1];
+    assert_eq!(chain.add_block(transactions.clone(), &private_key), Err(BlockError::Invalid(ValidationError::TooManyTransactions { index: 2 })));
+    assert_eq!(chain.len(), 2);
+
+    let previous_hash = chain.last_hash();
+    let difficulty = chain.next_difficulty();
+    let coinbase = Transaction::coinbase(public_key.clone(), block_reward(2), 2);
+    let transactions: Vec<Transaction> = std::iter::once(coinbase).chain(transactions).collect();
+    let (nonce, hash) = Block::mine_block(&transactions, &public_key, &previous_hash, 0, difficulty).unwrap();
+    let oversized = Block { transactions, nonce, miner: public_key, previous_hash, timestamp: 0, difficulty, hash, pruned_merkle_root: None, signature: Vec::new() };
+    let copy: Block = serde_json::from_value(serde_json::to_value(&oversized).unwrap()).unwrap();
+    assert_eq!(chain.append_block(oversized), Err(BlockError::Invalid(ValidationError::TooManyTransactions { index: 2 })));
+    chain.blocks.push(copy);
+    assert_eq!(chain.validate(), Err(ValidationError::TooManyTransactions { index: 2 }));
+  }
 
   #[test]
-  fn test_transaction_correct() {
-    let (private_key, public_key) = generate_key_pair();
-    let (_private_key2, public_key2) = generate_key_pair();
-    let transaction = Transaction::new(public_key, private_key, public_key2, 10.0, 1);
-    let is_good = transaction.verify();
-    assert_eq!(is_good, true);
+  fn test_block_reward_halves() {
+    assert_eq!(block_reward(0), BLOCK_REWARD);
+    assert_eq!(block_reward(HALVING_INTERVAL - 1), BLOCK_REWARD);
+    assert_eq!(block_reward(HALVING_INTERVAL), BLOCK_REWARD / 2);
+    assert_eq!(block_reward(2 * HALVING_INTERVAL), BLOCK_REWARD / 4);
+    assert_eq!(block_reward(64 * HALVING_INTERVAL), 0);
+    assert_eq!(block_reward(u64::MAX), 0);
+    // 50 coins in base units runs out after 33 halvings
+    assert_eq!(block_reward(32 * HALVING_INTERVAL), 1);
+    assert_eq!(block_reward(33 * HALVING_INTERVAL), 0);
   }
 
   #[test]
-  fn test_transaction_change_amount() {
-    let (private_key, public_key) = generate_key_pair();
-    let (_private_key2, public_key2) = generate_key_pair();
-    let mut transaction = Transaction::new(public_key, private_key, public_key2, 10.0, 1);
-    transaction.amount = 100.0;
-    let is_good = transaction.verify();
-    assert_eq!(is_good, false);
+  fn test_append_block_checks_reward() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let inflated = mine_with_coinbase(&chain, &private_key, block_reward(1) + 1);
+    assert_eq!(chain.append_block(inflated), Err(BlockError::Invalid(ValidationError::WrongReward { index: 1 })));
+    let correct = mine_with_coinbase(&chain, &private_key, block_reward(1));
+    assert_eq!(chain.append_block(correct), Ok(()));
   }
 
   #[test]
-  fn test_transaction_change_reciver() {
-    let (private_key, public_key) = generate_key_pair();
-    let (_private_key2, public_key2) = generate_key_pair();
-    let (_private_key2, public_key3) = generate_key_pair();
-    let mut transaction = Transaction::new(public_key, private_key, public_key2, 10.0, 1);
-    transaction.reciver = public_key3;
-    let is_good = transaction.verify();
-    assert_eq!(is_good, false);
+  fn test_validate_inflated_reward() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let inflated = mine_with_coinbase(&chain, &private_key, 1000 * COIN);
+    chain.blocks.push(inflated);
+    assert_eq!(chain.validate(), Err(ValidationError::WrongReward { index: 1 }));
   }
 
   #[test]
-  fn test_transaction_change_uid() {
-    let (private_key, public_key) = generate_key_pair();
-    let (_private_key2, public_key2) = generate_key_pair();
-    let (_private_key2, public_key3) = generate_key_pair();
-    let mut transaction = Transaction::new(public_key, private_key, public_key2, 10.0, 1);
-    transaction.uid = 2;
-    let is_good = transaction.verify();
-    assert_eq!(is_good, false);
+  fn test_append_block_rejects_invalid_blocks() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+
+    let mut block = mine_on_copy(&chain, Vec::new(), &private_key);
+    block.previous_hash = block.hash;
+    assert!(matches!(chain.append_block(block), Err(BlockError::Invalid(_))));
+
+    let mut block = mine_on_copy(&chain, Vec::new(), &private_key);
+    block.transactions[0].receiver = sentinel_key();
+    assert_eq!(chain.append_block(block), Err(BlockError::BadCoinbase));
+
+    let mut block = mine_on_copy(&chain, Vec::new(), &private_key);
+    block.transactions[0].amount = 1000 * COIN;
+    assert_eq!(chain.append_block(block), Err(BlockError::Invalid(ValidationError::WrongReward { index: 1 })));
+
+    let mut block = mine_on_copy(&chain, Vec::new(), &private_key);
+    block.difficulty = 4;
+    assert_eq!(chain.append_block(block), Err(BlockError::WrongDifficulty { expected: 8, found: 4 }));
+
+    let mut block = mine_on_copy(&chain, Vec::new(), &private_key);
+    block.nonce += 1;
+    assert_eq!(chain.append_block(block), Err(BlockError::Invalid(ValidationError::HashMismatch { index: 1 })));
+
+    assert_eq!(chain.len(), 1);
+  }
+
+  fn hashes_during(f: impl FnOnce()) -> usize {
+    let before = HASH_COUNT.with(|count| count.get());
+    f();
+    HASH_COUNT.with(|count| count.get()) - before
+  }
+
+  #[test]
+  fn test_validate_again_skips_checked_blocks() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    // keep retargeting from raising the difficulty while mining quickly
+    let mut chain = Blockchain::new(ChainParams { initial_difficulty: 4, target_block_seconds: 0, ..ChainParams::default() });
+    for _ in 0..100 {
+      chain.add_block(Vec::new(), &private_key).unwrap();
+    }
+    let first = hashes_during(|| assert_eq!(chain.validate(), Ok(())));
+    let second = hashes_during(|| assert_eq!(chain.validate(), Ok(())));
+    assert!(first >= 101);
+    assert!(second < first / 10);
+  }
+
+  #[test]
+  fn test_validate_checks_blocks_added_since_last_time() {
+    let mut chain = build_validation_chain();
+    assert_eq!(chain.validate(), Ok(()));
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.blocks[3].hash = ZERO_HASH;
+    assert_eq!(chain.validate(), Err(ValidationError::HashMismatch { index: 3 }));
+  }
+
+  #[test]
+  fn test_transaction_hash_is_memoized() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transactions: Vec<Transaction> = (0..4)
+      .map(|uid| Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, uid))
+      .collect();
+    let root = merkle_root(&transactions);
+    assert_eq!(hashes_during(|| assert_eq!(merkle_root(&transactions), root)), 0);
+  }
+
+  #[test]
+  fn test_genesis_is_identical_everywhere() {
+    let first = Blockchain::default();
+    let second = Blockchain::new(ChainParams::default());
+    assert_eq!(serde_json::to_vec(&first.blocks[0]).unwrap(), serde_json::to_vec(&second.blocks[0]).unwrap());
+    assert_eq!(first.last_hash(), second.last_hash());
+    assert_eq!(first.last_hash(), first.genesis_hash());
+  }
+
+  #[test]
+  fn test_testnet_params() {
+    let testnet = ChainParams::testnet();
+    let (private_key, public_key) = testnet.generate_key_pair().unwrap();
+    assert_eq!(public_key.size() * 8, MIN_KEY_BITS);
+    let mut chain = Blockchain::new(testnet);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(chain.last_block().difficulty, 1);
+    assert_eq!(chain.validate(), Ok(()));
+    assert_ne!(chain.genesis_hash(), Blockchain::default().genesis_hash());
+
+    let mainnet = ChainParams::mainnet();
+    assert_eq!(mainnet, ChainParams::default());
+    assert_eq!(mainnet.initial_difficulty, DEFAULT_DIFFICULTY);
+    assert_eq!(mainnet.target_block_seconds, TARGET_BLOCK_SECONDS);
+    assert_eq!(mainnet.block_reward(0), BLOCK_REWARD);
+    assert_eq!(mainnet.key_bits, DEFAULT_KEY_BITS);
+    // the key size isn't part of the genesis block
+    assert_eq!(ChainParams { key_bits: MIN_KEY_BITS, ..mainnet.clone() }.genesis_hash(), mainnet.genesis_hash());
+  }
+
+  #[test]
+  fn test_derived_uids_never_collide() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let address = Address::from_public_key(&public_key);
+    let address2 = Address::from_public_key(&public_key2);
+    for counter in [0, 1, u32::MAX] {
+      assert_ne!(derive_uid(&address, counter), derive_uid(&address2, counter));
+    }
+
+    let mut chain = Blockchain::new(ChainParams { initial_difficulty: 8, derived_uids: true, ..ChainParams::default() });
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key2).unwrap();
+    let first = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, derive_uid(&address, 7));
+    let second = Transaction::new(public_key2.clone(), private_key2.clone(), public_key.clone(), COIN, derive_uid(&address2, 7));
+    assert!(first.uid_encodes_sender() && second.uid_encodes_sender());
+    // a uid made for someone else, or none at all, doesn't pass
+    let borrowed = Transaction::new(public_key2.clone(), private_key2, public_key.clone(), COIN, derive_uid(&address, 8));
+    assert_eq!(chain.can_accept(&borrowed), Err(RejectReason::ForeignUid));
+    let plain = Transaction::new(public_key.clone(), private_key.clone(), public_key2, COIN, 1);
+    assert_eq!(chain.add_block(vec![plain.clone()], &private_key), Err(BlockError::ForeignUid { uid: 1 }));
+    chain.add_block(vec![first, second], &private_key).unwrap();
+    assert_eq!(chain.validate(), Ok(()));
+
+    let mut template = chain.block_template(Vec::new(), public_key).unwrap();
+    template.transactions.push(plain);
+    chain.blocks.push(template.mine(&AtomicBool::new(false)).unwrap().unwrap());
+    assert_eq!(chain.validate(), Err(ValidationError::ForeignUid { index: 4 }));
+  }
+
+  #[test]
+  fn test_proof_of_authority() {
+    let (validator_key, validator) = generate_key_pair().unwrap();
+    let (outsider_key, outsider) = generate_key_pair().unwrap();
+    let params = ChainParams { consensus: ConsensusMode::ProofOfAuthority { validators: vec![validator.clone()] }, ..ChainParams::default() };
+    let mut chain = Blockchain::new(params.clone());
+    assert_ne!(chain.genesis_hash(), Blockchain::default().genesis_hash());
+    chain.add_block(Vec::new(), &validator_key).unwrap();
+    assert_eq!(chain.last_block().difficulty, 0);
+    assert!(chain.last_block().verify_signature());
+    assert_eq!(chain.validate(), Ok(()));
+
+    let unauthorized = Err(BlockError::Invalid(ValidationError::Unauthorized { index: 2 }));
+    assert_eq!(chain.add_block(Vec::new(), &outsider_key), unauthorized);
+    assert_eq!(chain.block_template(Vec::new(), outsider).err(), Some(BlockError::Invalid(ValidationError::Unauthorized { index: 2 })));
+    // a validator named as miner doesn't help without their signature
+    let template = chain.block_template(Vec::new(), validator.clone()).unwrap();
+    let mut forged = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    forged.sign(&outsider_key);
+    assert_eq!(chain.append_block(forged), Err(BlockError::Invalid(ValidationError::BadBlockSignature { index: 2 })));
+    assert_eq!(chain.len(), 2);
+
+    // and the params survive a save, validators included
+    let loaded: Blockchain = serde_json::from_str(&serde_json::to_string(&chain).unwrap()).unwrap();
+    assert_eq!(*loaded.params(), params);
+    assert_eq!(loaded.validate(), Ok(()));
+  }
+
+  #[test]
+  fn test_genesis_depends_on_params() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mainnet = Blockchain::with_difficulty(8);
+    let mut other = Blockchain::new(ChainParams { initial_difficulty: 8, initial_reward: COIN, ..ChainParams::default() });
+    assert_ne!(mainnet.genesis_hash(), other.genesis_hash());
+    assert_ne!(mainnet.genesis_hash(), Blockchain::with_difficulty(9).genesis_hash());
+
+    // so a block mined on one can't be appended to the other, even at the same height
+    other.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(other.last_block().transactions[0].amount, COIN);
+    let mut mainnet_copy = Blockchain::with_difficulty(8);
+    assert!(mainnet_copy.append_block(other.last_block().clone()).is_err());
+    assert!(!mainnet_copy.replace_if_better(other));
+    assert_eq!(mainnet_copy.last_hash(), mainnet.last_hash());
+  }
+
+  #[test]
+  fn test_validate_wrong_genesis() {
+    let mut chain = build_validation_chain();
+    chain.blocks.remove(0);
+    assert_eq!(chain.validate(), Err(ValidationError::WrongGenesis));
+  }
+
+  #[test]
+  fn test_replace_if_better_takes_longer_chain() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = build_validation_chain();
+    let mut candidate = Blockchain::with_difficulty(8);
+    for _ in 0..3 {
+      candidate.add_block(Vec::new(), &private_key).unwrap();
+    }
+    let tip = candidate.last_hash();
+    assert!(chain.replace_if_better(candidate));
+    assert_eq!(chain.len(), 4);
+    assert_eq!(chain.last_hash(), tip);
+    assert!(chain.seen_uids.is_empty());
+  }
+
+  #[test]
+  fn test_reorg_to_heavier_branch() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let mut branch = chain.truncated(3);
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 1);
+    let kept = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 2);
+    chain.add_block(vec![payment.clone(), kept.clone()], &private_key).unwrap();
+
+    // the rival branch forks at height 2 and picks up only one of the two payments
+    branch.add_block(vec![kept], &private_key2).unwrap();
+    assert_eq!(chain.reorg_to(branch.blocks[3..].to_vec()), Err(ReorgError::NotEnoughWork));
+    branch.add_block(Vec::new(), &private_key2).unwrap();
+    let returned = chain.reorg_to(branch.blocks[3..].to_vec()).unwrap();
+    assert_eq!(returned, vec![payment]);
+    assert_eq!(chain.len(), 5);
+    assert_eq!(chain.last_hash(), branch.last_hash());
+    assert_eq!(chain.balance_of(&public_key2), 2 * BLOCK_REWARD + COIN);
+    assert!(!chain.seen_uids.contains(&1));
+    assert!(chain.seen_uids.contains(&2));
+    assert_eq!(chain.validate(), Ok(()));
+
+    assert_eq!(chain.reorg_to(Vec::new()), Err(ReorgError::NoBlocks));
+    let mut orphan = branch.blocks[4].clone();
+    orphan.previous_hash = ZERO_HASH;
+    assert_eq!(chain.reorg_to(vec![orphan]), Err(ReorgError::UnknownAncestor));
+  }
+
+  #[test]
+  fn test_total_work() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(4);
+    assert_eq!(chain.total_work(), 1);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(chain.total_work(), 1 + 16 + 16);
+    chain.blocks[2].difficulty = 256;
+    assert_eq!(chain.total_work(), u128::MAX);
+  }
+
+  #[test]
+  fn test_replace_if_better_prefers_work_over_length() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    // slow blocks retarget down to 3 bits, fast ones up to 5
+    let mut long = Blockchain::with_difficulty(4);
+    for i in 0..RETARGET_INTERVAL as u64 + 2 {
+      long.add_block_at(Vec::new(), &private_key, 1000 * i).unwrap();
+    }
+    let mut heavy = Blockchain::with_difficulty(4);
+    for i in 0..RETARGET_INTERVAL as u64 + 1 {
+      heavy.add_block_at(Vec::new(), &private_key, i).unwrap();
+    }
+    assert!(heavy.len() < long.len());
+    assert!(heavy.total_work() > long.total_work());
+    let tip = heavy.last_hash();
+    assert!(long.replace_if_better(heavy));
+    assert_eq!(long.last_hash(), tip);
+  }
+
+  #[test]
+  fn test_replace_if_better_rejects_equal_length() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = build_validation_chain();
+    let tip = chain.last_hash();
+    let mut candidate = Blockchain::with_difficulty(8);
+    candidate.add_block(Vec::new(), &private_key).unwrap();
+    candidate.add_block(Vec::new(), &private_key).unwrap();
+    assert!(!chain.replace_if_better(candidate));
+    assert_eq!(chain.last_hash(), tip);
+  }
+
+  #[test]
+  fn test_replace_if_better_rejects_longer_invalid_chain() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let tip = chain.last_hash();
+    let mut candidate = build_validation_chain();
+    candidate.add_block(Vec::new(), &private_key).unwrap();
+    candidate.blocks[2].transactions[1].amount = 40 * COIN;
+    assert!(!chain.replace_if_better(candidate));
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain.last_hash(), tip);
+  }
+
+  #[test]
+  fn test_validate_valid_chain() {
+    let chain = build_validation_chain();
+    assert_eq!(chain.validate(), Ok(()));
+  }
+
+  #[test]
+  fn test_validate_tampered_amount() {
+    let mut chain = build_validation_chain();
+    chain.blocks[2].transactions[1].amount = 1000 * COIN;
+    assert_eq!(chain.validate(), Err(ValidationError::InvalidTransaction { index: 2 }));
+  }
+
+  #[test]
+  fn test_validate_broken_link() {
+    let mut chain = build_validation_chain();
+    // a properly mined block that points somewhere other than block 1
+    let block = &chain.blocks[2];
+    let bogus_previous = block.hash;
+    let (nonce, hash) = Block::mine_block(&block.transactions, &block.miner, &bogus_previous, block.timestamp, block.difficulty).unwrap();
+    let block = &mut chain.blocks[2];
+    block.previous_hash = bogus_previous;
+    block.nonce = nonce;
+    block.hash = hash;
+    assert_eq!(chain.validate(), Err(ValidationError::BrokenLink { index: 2 }));
+  }
+
+  #[test]
+  fn test_validate_wrong_hash() {
+    let mut chain = build_validation_chain();
+    chain.blocks[1].hash = ZERO_HASH;
+    assert_eq!(chain.validate(), Err(ValidationError::HashMismatch { index: 1 }));
+  }
+
+  #[test]
+  fn test_validate_insufficient_work() {
+    let mut chain = build_validation_chain();
+    let target = target_from_leading_zero_bits(8);
+    let block = &chain.blocks[2];
+    let root = merkle_root(&block.transactions);
+    let miner = public_key_der(&block.miner);
+    let nonce = (0_u64..).find(|&nonce| !Block::check_block(&root, &miner, nonce, &block.previous_hash, block.timestamp, &target).0).unwrap();
+    let (_, hash) = Block::check_block(&root, &miner, nonce, &block.previous_hash, block.timestamp, &target);
+    let block = &mut chain.blocks[2];
+    block.nonce = nonce;
+    block.hash = hash;
+    assert_eq!(chain.validate(), Err(ValidationError::InsufficientWork { index: 2 }));
+  }
+
+  #[test]
+  fn test_validate_checks_difficulty_schedule() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let mut template = chain.block_template(Vec::new(), public_key).unwrap();
+    // any hash meets a difficulty of 0, so only the retarget schedule catches it
+    template.difficulty = 0;
+    let mut block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    block.sign(&private_key);
+    assert_eq!(chain.append_block(block.clone()), Err(BlockError::WrongDifficulty { expected: 8, found: 0 }));
+    chain.blocks.push(block);
+    assert_eq!(chain.validate(), Err(ValidationError::WrongDifficulty { index: 1 }));
+  }
+
+  #[test]
+  fn test_validate_catches_overspend() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let overspend = Transaction::new(public_key.clone(), private_key.clone(), public_key2, BLOCK_REWARD + 1, 1);
+    let mut template = chain.block_template(Vec::new(), public_key).unwrap();
+    template.transactions.push(overspend);
+    let mut block = template.mine(&AtomicBool::new(false)).unwrap().unwrap();
+    block.sign(&private_key);
+    assert_eq!(chain.append_block(block.clone()), Err(BlockError::InsufficientFunds { uid: 1 }));
+    chain.blocks.push(block);
+    assert_eq!(chain.validate(), Err(ValidationError::Overspend { index: 2 }));
+  }
+
+  #[test]
+  fn test_mine_block_nonce_exhausted() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let result = Block::mine_nonces(&[], &public_key, &ZERO_HASH, 0, 256, 0..=1000);
+    assert_eq!(result, Err(MineError::NonceExhausted));
+  }
+
+  #[test]
+  fn test_check_block_reproduces_mined_hash() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let (nonce, hash) = Block::mine_block(&[], &public_key, &ZERO_HASH, 0, 8).unwrap();
+    let target = target_from_leading_zero_bits(8);
+    let miner = public_key_der(&public_key);
+    assert_eq!(Block::check_block(&merkle_root(&[]), &miner, nonce, &ZERO_HASH, 0, &target), (true, hash));
+    let header = [&merkle_root(&[]).as_bytes()[..], &miner, &nonce.to_be_bytes(), ZERO_HASH.as_bytes(), &0_u64.to_be_bytes(), target.as_bytes()].concat();
+    assert_eq!(block_hash(&header, DOUBLE_SHA256_BLOCKS), hash);
+  }
+
+  #[test]
+  fn test_verify_pow() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let block = &mut chain.blocks[1];
+    assert!(block.verify_pow());
+    block.nonce += 1;
+    assert!(!block.verify_pow());
+  }
+
+  #[test]
+  fn test_block_header() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    chain.add_block(vec![Transaction::new(public_key.clone(), private_key.clone(), public_key2, COIN, 1)], &private_key).unwrap();
+    let block = chain.last_block();
+    let header = block.header();
+    assert_eq!(header.hash(), *block.hash());
+    assert!(header.verify_pow());
+    assert_eq!(header.previous_hash, *chain.block_at(1).unwrap().hash());
+    assert_eq!(Block::genesis(chain.params()).header().hash(), chain.genesis_hash());
+
+    // a header stands on its own once serialized
+    let decoded: BlockHeader = serde_json::from_str(&serde_json::to_string(&header).unwrap()).unwrap();
+    assert_eq!(decoded, header);
+    assert_eq!(decoded.hash(), *block.hash());
+    let mut tampered = header;
+    tampered.nonce += 1;
+    assert_ne!(tampered.hash(), *block.hash());
+  }
+
+  #[test]
+  fn test_reconstructed_block_nonce_reproduces_hash() {
+    let (private_key, _public_key) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    // a peer only ever sees the serialized block, so the nonce has to travel with it
+    let block: Block = serde_json::from_str(&serde_json::to_string(chain.last_block()).unwrap()).unwrap();
+    let target = target_from_leading_zero_bits(block.difficulty());
+    let (meets_target, hash) = Block::check_block(&merkle_root(block.transactions()), &public_key_der(block.miner()), block.nonce(), block.previous_hash(), block.timestamp(), &target);
+    assert!(meets_target);
+    assert_eq!(hash, *block.hash());
+    assert!(block.verify_pow());
+  }
+
+  #[test]
+  fn test_mine_cancellable() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let cancel = AtomicBool::new(false);
+    assert!(Block::mine_cancellable(&[], &public_key, &ZERO_HASH, 0, 8, &cancel).unwrap().is_some());
+
+    // 256 leading zero bits will never be found, so only the flag can stop it
+    let started = std::time::Instant::now();
+    let result = std::thread::scope(|scope| {
+      let miner = scope.spawn(|| Block::mine_cancellable(&[], &public_key, &ZERO_HASH, 0, 256, &cancel));
+      std::thread::sleep(std::time::Duration::from_millis(50));
+      cancel.store(true, Ordering::SeqCst);
+      miner.join().unwrap()
+    });
+    assert_eq!(result, Ok(None));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(Block::mine_cancellable(&[], &public_key, &ZERO_HASH, 0, 8, &cancel), Ok(None));
+  }
+
+  #[test]
+  fn test_mine_with_progress() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let mut reported = Vec::new();
+    let (nonce, hash) = Block::mine_with_progress(&[], &public_key, &ZERO_HASH, 0, 10, 1, |attempts| reported.push(attempts)).unwrap();
+    let target = target_from_leading_zero_bits(10);
+    assert_eq!(Block::check_block(&merkle_root(&[]), &public_key_der(&public_key), nonce, &ZERO_HASH, 0, &target), (true, hash));
+    // nonces are tried from 0 up, so the winner was attempt nonce + 1
+    assert_eq!(reported, (1..=nonce + 1).collect::<Vec<u64>>());
+
+    let mut calls = 0;
+    let found = Block::mine_with_progress(&[], &public_key, &ZERO_HASH, 0, 10, 4, |_| calls += 1).unwrap();
+    assert_eq!(found, (nonce, hash));
+    assert_eq!(calls, (nonce + 1) / 4);
+  }
+
+  #[test]
+  fn test_mine_block_parallel() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let (nonce, hash) = Block::mine_block_parallel(&[], &public_key, &ZERO_HASH, 0, 12, 4).unwrap();
+    let target = target_from_leading_zero_bits(12);
+    assert_eq!(Block::check_block(&merkle_root(&[]), &public_key_der(&public_key), nonce, &ZERO_HASH, 0, &target), (true, hash));
+    assert!(hash <= target);
+  }
+
+  #[test]
+  fn test_mine_block_parallel_one_thread_matches_sequential() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let sequential = Block::mine_nonces(&[], &public_key, &ZERO_HASH, 0, 8, 0..=u64::MAX);
+    assert_eq!(Block::mine_block_parallel(&[], &public_key, &ZERO_HASH, 0, 8, 1), sequential);
+  }
+
+  #[test]
+  fn test_mine_difficulty_one_byte() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let (_, hash) = Block::mine_block(&[], &public_key, &ZERO_HASH, 0, 8).unwrap();
+    assert_eq!(hash.as_bytes()[0], 0);
+  }
+
+  #[test]
+  fn test_mine_difficulty_two_bytes() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let (_, hash) = Block::mine_block(&[], &public_key, &ZERO_HASH, 0, 16).unwrap();
+    assert_eq!(&hash.as_bytes()[..2], &[0, 0]);
+  }
+
+  #[test]
+  fn test_expected_attempts() {
+    assert_eq!(expected_attempts(0), 1.0);
+    assert_eq!(expected_attempts(DEFAULT_DIFFICULTY), 4_294_967_296.0);
+    // a whole extra zero byte is 256 times the work
+    assert_eq!(expected_attempts(16) / expected_attempts(8), 256.0);
+    assert_eq!(estimate_seconds(20, 1024.0), 1024.0);
+    assert!(estimate_seconds(8, 0.0).is_infinite());
+  }
+
+  #[test]
+  fn test_target_nine_bits_between_one_and_two_bytes() {
+    let eight = target_from_leading_zero_bits(8);
+    let nine = target_from_leading_zero_bits(9);
+    let sixteen = target_from_leading_zero_bits(16);
+    assert_eq!(&eight.as_bytes()[..2], &[0x00, 0xff]);
+    assert_eq!(&nine.as_bytes()[..2], &[0x00, 0x7f]);
+    assert!(nine < eight);
+    assert!(nine > sixteen);
+    let mut hash = [0xff; 32];
+    hash[0] = 0x00;
+    hash[1] = 0x80;
+    let hash = SHA256Hash::from(hash);
+    assert!(hash <= eight);
+    assert!(hash > nine);
+  }
+
+  #[test]
+  fn test_sha256() {
+    assert_eq!(sha256(b"hello").to_string(), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+    assert_eq!(sha256(b"").to_string(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(sha256_many(&[b"he", b"", b"llo"]), sha256(b"hello"));
+  }
+
+  #[test]
+  fn test_double_sha256() {
+    assert_eq!(double_sha256(b"").to_string(), "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456");
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    let target = target_from_leading_zero_bits(0);
+    let miner = public_key_der(&public_key);
+    let header = [&merkle_root(&[]).as_bytes()[..], &miner, &0_u64.to_be_bytes(), ZERO_HASH.as_bytes(), &0_u64.to_be_bytes(), target.as_bytes()].concat();
+    let single = block_hash(&header, false);
+    let double = block_hash(&header, true);
+    assert_ne!(single, double);
+    let (_, hash) = Block::check_block(&merkle_root(&[]), &miner, 0, &ZERO_HASH, 0, &target);
+    assert_eq!(hash, if DOUBLE_SHA256_BLOCKS { double } else { single });
+  }
+
+  #[test]
+  fn test_hash_string_round_trip() {
+    let hash = sha256(b"hello");
+    let hex = hash.to_string();
+    assert_eq!(hex, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+    assert_eq!(format!("{:?}", hash), hex);
+    assert_eq!(hex.parse::<SHA256Hash>(), Ok(hash));
+  }
+
+  #[test]
+  fn test_hash_parse_errors() {
+    assert_eq!("abcd".parse::<SHA256Hash>(), Err(ParseHashError::InvalidLength(4)));
+    let not_hex = "g".repeat(64);
+    assert_eq!(not_hex.parse::<SHA256Hash>(), Err(ParseHashError::InvalidHex));
+    let signed = format!("+{}", "0".repeat(63));
+    assert_eq!(signed.parse::<SHA256Hash>(), Err(ParseHashError::InvalidHex));
+  }
+
+  #[test]
+  fn test_mine_difficulty_zero_accepts_any_hash() {
+    let (_private_key, public_key) = generate_key_pair().unwrap();
+    // with one thread the search is sequential, so the very first nonce wins
+    let (nonce, _) = Block::mine_block_parallel(&[], &public_key, &ZERO_HASH, 0, 0, 1).unwrap();
+    assert_eq!(nonce, 0);
+  }
+
+  #[test]
+  fn test_generate_key_pair_with_bits() {
+    let (private_key, public_key) = generate_key_pair_with_bits(1024).unwrap();
+    assert_eq!(public_key.size(), 128);
+    let signature = sign_message("hello", &private_key);
+    assert!(verify_message("hello", &signature, &public_key));
+    assert!(!verify_message("goodbye", &signature, &public_key));
+  }
+
+  #[test]
+  fn test_pss_signatures() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message_with("hello", &private_key, SignatureScheme::Pss);
+    assert_eq!(verify_message_with("hello", &signature, &public_key, SignatureScheme::Pss), Ok(()));
+    assert_eq!(verify_message_with("goodbye", &signature, &public_key, SignatureScheme::Pss), Err(VerifyError::SignatureMismatch));
+    // the padding isn't interchangeable in either direction
+    assert!(!verify_message("hello", &signature, &public_key));
+    let pkcs1v15 = sign_message("hello", &private_key);
+    assert!(verify_message_with("hello", &pkcs1v15, &public_key, SignatureScheme::Pss).is_err());
+  }
+
+  #[test]
+  fn test_transaction_records_scheme() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_, public_key2) = generate_key_pair().unwrap();
+    let transaction = TransactionBuilder::new()
+      .sender(public_key.clone())
+      .receiver(public_key2)
+      .amount(COIN)
+      .uid(1)
+      .scheme(SignatureScheme::Pss)
+      .sign_with(private_key)
+      .build()
+      .unwrap();
+    assert_eq!(transaction.scheme(), SignatureScheme::Pss);
+    assert!(transaction.verify());
+    let decoded: Transaction = serde_json::from_str(&serde_json::to_string(&transaction).unwrap()).unwrap();
+    assert!(decoded.verify());
+    let mut relabelled = transaction.clone();
+    relabelled.scheme = SignatureScheme::Pkcs1v15;
+    assert!(!relabelled.verify());
+  }
+
+  #[test]
+  fn test_generate_key_pair_rejects_small_keys() {
+    assert!(matches!(generate_key_pair_with_bits(512), Err(KeyGenError::TooFewBits(512))));
   }
 
   #[test]
   fn test_signing_correct() {
-    let (private_key, public_key) = generate_key_pair();
-    let signature = sign_message("hello".to_string(), &private_key);
-    let is_good = verify_message("hello".to_string(), &signature, &public_key);
-    assert_eq!(is_good, true);
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message("hello", &private_key);
+    let is_good = verify_message("hello", &signature, &public_key);
+    assert!(is_good);
   }
 
   #[test]
   fn test_signing_message_change() {
-    let (private_key, public_key) = generate_key_pair();
-    let signature = sign_message("hello".to_string(), &private_key);
-    let is_good = verify_message("goodbye".to_string(), &signature, &public_key);
-    assert_eq!(is_good, false);
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message("hello", &private_key);
+    let is_good = verify_message("goodbye", &signature, &public_key);
+    assert!(!is_good);
   }
 
   #[test]
   fn test_signing_bad_signature() {
-    let (private_key, public_key) = generate_key_pair();
-    let mut signature = sign_message("hello".to_string(), &private_key);
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let mut signature = sign_message("hello", &private_key);
     signature[0] = if signature[0] == 15 { 16 } else { 15 };
-    let is_good = verify_message("hello".to_string(), &signature, &public_key);
-    assert_eq!(is_good, false);
+    let is_good = verify_message("hello", &signature, &public_key);
+    assert!(!is_good);
+  }
+
+  #[test]
+  fn test_verify_detailed_truncated_signature() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message("hello", &private_key);
+    assert_eq!(verify_message_detailed("hello", &signature[..100], &public_key), Err(VerifyError::BadSignatureFormat));
+    assert_eq!(verify_message_detailed("hello", &[], &public_key), Err(VerifyError::BadSignatureFormat));
+  }
+
+  #[test]
+  fn test_verify_detailed_wrong_signature() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message("hello", &private_key);
+    assert_eq!(verify_message_detailed("hello", &signature, &public_key), Ok(()));
+    assert_eq!(verify_message_detailed("goodbye", &signature, &public_key), Err(VerifyError::SignatureMismatch));
   }
 }
\ No newline at end of file
@@ -0,0 +1,6 @@
+pub mod network;
+pub mod blockchain;
+pub mod mempool;
+pub mod rpc;
+pub mod txlog;
+pub mod wallet;
@@ -1,25 +1,354 @@
-mod network;
-mod blockchain;
-use std::env;
-use sha2::{Sha256, Digest};
-//use crate::network;
+use clap::{Parser, Subcommand, ValueEnum};
+use rsa::RsaPublicKey;
+use shitcoin::blockchain::{self, Address, Blockchain, ChainParams, Transaction};
+use shitcoin::mempool::Mempool;
+use shitcoin::network::{self, NetMessage, Node};
+use shitcoin::rpc;
+use shitcoin::wallet;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug, PartialEq)]
+#[command(name = "shitcoin", about = "A toy blockchain node and wallet")]
+struct Cli {
+    /// file the blockchain is loaded from and saved to
+    #[arg(long, global = true, default_value = "chain.json")]
+    chain: PathBuf,
+    /// file holding transactions waiting to be mined
+    #[arg(long, global = true, default_value = "mempool.json")]
+    mempool: PathBuf,
+    /// rules the chain follows, and the size of generated keys
+    #[arg(long, global = true, value_enum, default_value_t = Network::Mainnet)]
+    network: Network,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Network {
+    Mainnet,
+    Testnet,
+}
+
+impl Network {
+    fn params(self) -> ChainParams {
+        match self {
+            Network::Mainnet => ChainParams::mainnet(),
+            Network::Testnet => ChainParams::testnet(),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// run a node that accepts blocks and transactions from peers
+    Node {
+        #[arg(long)]
+        listen: String,
+        /// peers to connect to on startup
+        #[arg(long)]
+        peer: Vec<String>,
+        /// address to answer JSON queries on
+        #[arg(long)]
+        rpc: Option<String>,
+    },
+    /// mine a block from the mempool
+    Mine {
+        /// private key file of the miner, who signs the block and is paid the reward
+        #[arg(long)]
+        key: PathBuf,
+        #[arg(long, default_value_t = 100)]
+        max_transactions: usize,
+    },
+    /// sign a transaction and queue it in the mempool
+    Send {
+        /// public key file of the recipient
+        #[arg(long)]
+        to: PathBuf,
+        /// coins to send, e.g. 1.5
+        #[arg(long, value_parser = blockchain::parse_amount)]
+        amount: u64,
+        #[arg(long, value_parser = blockchain::parse_amount, default_value = "0")]
+        fee: u64,
+        /// private key file of the sender
+        #[arg(long)]
+        key: PathBuf,
+        /// node to broadcast the transaction to
+        #[arg(long)]
+        peer: Option<String>,
+    },
+    /// print the balance of a key
+    Balance {
+        #[arg(long)]
+        key: PathBuf,
+    },
+    /// generate a new key pair and write the private key to a file
+    Keygen {
+        #[arg(long)]
+        out: PathBuf,
+        /// overwrite the file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+// a chain file keeps the params it was started with, which have to be the network's
+fn load_chain(path: &Path, network: Network) -> Result<Blockchain, Box<dyn Error>> {
+    let params = network.params();
+    if !path.exists() {
+        return Ok(Blockchain::new(params));
+    }
+    let chain = Blockchain::load(path)?;
+    if *chain.params() != params {
+        let message = format!("{} was started on a different network than --network", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+    }
+    Ok(chain)
+}
+
+fn load_mempool(path: &Path) -> Result<Mempool, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(Mempool::new());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save_mempool(mempool: &Mempool, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, serde_json::to_string(mempool)?)?;
+    Ok(())
+}
+
+// accepts either a public key file or a private key file
+fn load_public_key(path: &Path) -> Result<RsaPublicKey, Box<dyn Error>> {
+    match wallet::load_public_key(path) {
+        Ok(key) => Ok(key),
+        Err(_) => Ok(RsaPublicKey::from(&wallet::load_private_key(path)?)),
+    }
+}
+
+// writes a fresh private key to out and returns its address
+fn keygen(out: &Path, force: bool, params: &ChainParams) -> Result<Address, Box<dyn Error>> {
+    if out.exists() && !force {
+        let message = format!("{} already exists, pass --force to overwrite it", out.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+    }
+    let (private_key, public_key) = params.generate_key_pair()?;
+    wallet::save_private_key(&private_key, out)?;
+    Ok(Address::from_public_key(&public_key))
+}
+
+fn run(cli: Cli, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    match cli.command {
+        Command::Node { listen, peer, rpc } => {
+            let node = Node::new(load_chain(&cli.chain, cli.network)?);
+            let listener = TcpListener::bind(&listen)?;
+            let server = node.listen(listener);
+            if let Some(addr) = rpc {
+                rpc::serve(node.clone(), TcpListener::bind(&addr)?)?;
+                writeln!(out, "Answering queries on {}", addr)?;
+            }
+            for addr in &peer {
+                node.connect(addr)?;
+            }
+            writeln!(out, "Listening on {} at height {}", listen, node.chain().len() - 1)?;
+            server.join().expect("listener thread panicked");
+        }
+        Command::Mine { key, max_transactions } => {
+            let mut chain = load_chain(&cli.chain, cli.network)?;
+            let mut mempool = load_mempool(&cli.mempool)?;
+            let miner = wallet::load_private_key(&key)?;
+            let mut transactions = mempool.drain_for_block(max_transactions.min(chain.params().max_block_txs));
+            // pending transactions are only checked one at a time, so together they can still fail;
+            // the ones that can never be mined are dropped so the next try doesn't fail the same way
+            while let Err(error) = chain.add_block(transactions.clone(), &miner) {
+                let uid = error.unminable_uid().ok_or(error)?;
+                writeln!(out, "Dropped transaction {} that can't be mined", uid)?;
+                transactions.retain(|transaction| transaction.uid() != uid);
+            }
+            let count = transactions.len();
+            chain.save(&cli.chain)?;
+            save_mempool(&mempool, &cli.mempool)?;
+            writeln!(out, "Mined block {} with {} transactions", chain.last_hash(), count)?;
+        }
+        Command::Send { to, amount, fee, key, peer } => {
+            let private_key = wallet::load_private_key(&key)?;
+            let sender = RsaPublicKey::from(&private_key);
+            let receiver = load_public_key(&to)?;
+            let uid = blockchain::derive_uid(&Address::from_public_key(&sender), rand::random());
+            let transaction = Transaction::with_fee(sender, private_key, receiver, amount, fee, uid);
+            // queued before the peer hears about it, so a doomed transaction goes nowhere
+            let chain = load_chain(&cli.chain, cli.network)?;
+            let mut mempool = load_mempool(&cli.mempool)?;
+            mempool.add_checked(transaction.clone(), &chain)?;
+            if let Some(addr) = peer {
+                let mut stream = TcpStream::connect(addr)?;
+                network::set_timeouts(&stream, network::DEFAULT_TIMEOUT)?;
+                network::handshake(&mut stream, chain.len() as u64 - 1, None)?;
+                network::send_message(&mut stream, &NetMessage::Tx(transaction))?;
+            }
+            save_mempool(&mempool, &cli.mempool)?;
+            writeln!(out, "Queued transaction {} sending {}", uid, blockchain::format_amount(amount))?;
+        }
+        Command::Balance { key } => {
+            let chain = load_chain(&cli.chain, cli.network)?;
+            let key = load_public_key(&key)?;
+            writeln!(out, "{}", blockchain::format_amount(chain.balance_of(&key)))?;
+        }
+        Command::Keygen { out: path, force } => {
+            let address = keygen(&path, force, &cli.network.params())?;
+            writeln!(out, "{}", address)?;
+        }
+    }
+    Ok(())
+}
+
+// node events go to stderr so they don't mix with command output
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
 
 fn main() {
-    /*
-    let args: Vec<String> = env::args().collect();
-    println!("{:?}", args);
-    match &args[1][..] {
-        "client" => network::client(),
-        "server" => network::server(),
-        _ => (),
-    }
-    let (private_key, public_key) = blockchain::generate_key_pair();
-    let (private_key2, public_key2) = blockchain::generate_key_pair();
-    let foo = blockchain::Transaction::new(public_key, private_key, public_key2, 10.0, 1);
-    */
-
-    let mut hasher = Sha256::new();
-    hasher.update("hello");
-    let hash = blockchain::SHA256Hash::from(hasher.finalize());
-    println!("{:?}", hash);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+    if let Err(error) = run(Cli::parse(), &mut io::stdout()) {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shitcoin::blockchain::COIN;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("shitcoin").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_node() {
+        let cli = parse(&["node", "--listen", "0.0.0.0:3333", "--peer", "a:1", "--peer", "b:2"]);
+        assert_eq!(cli, Cli {
+            chain: PathBuf::from("chain.json"),
+            mempool: PathBuf::from("mempool.json"),
+            network: Network::Mainnet,
+            command: Command::Node { listen: "0.0.0.0:3333".to_string(), peer: vec!["a:1".to_string(), "b:2".to_string()], rpc: None },
+        });
+        let cli = parse(&["node", "--listen", "0.0.0.0:3333", "--rpc", "127.0.0.1:3334"]);
+        assert!(matches!(cli.command, Command::Node { rpc: Some(addr), .. } if addr == "127.0.0.1:3334"));
+    }
+
+    #[test]
+    fn test_parse_mine() {
+        let cli = parse(&["--chain", "other.json", "mine", "--key", "miner.pem"]);
+        assert_eq!(cli.chain, PathBuf::from("other.json"));
+        assert_eq!(cli.command, Command::Mine { key: PathBuf::from("miner.pem"), max_transactions: 100 });
+    }
+
+    #[test]
+    fn test_parse_send() {
+        let cli = parse(&["send", "--to", "bob.pem", "--amount", "1.5", "--key", "alice.pem", "--mempool", "pool.json"]);
+        assert_eq!(cli.mempool, PathBuf::from("pool.json"));
+        assert_eq!(cli.command, Command::Send {
+            to: PathBuf::from("bob.pem"),
+            amount: 3 * COIN / 2,
+            fee: 0,
+            key: PathBuf::from("alice.pem"),
+            peer: None,
+        });
+        assert!(Cli::try_parse_from(["shitcoin", "send", "--to", "bob.pem", "--amount", "lots", "--key", "alice.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(parse(&["balance", "--key", "alice.pem"]).network, Network::Mainnet);
+        let cli = parse(&["balance", "--key", "alice.pem", "--network", "testnet"]);
+        assert_eq!(cli.network.params(), ChainParams::testnet());
+        assert!(Cli::try_parse_from(["shitcoin", "--network", "devnet", "balance", "--key", "alice.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_keygen() {
+        let cli = parse(&["keygen", "--out", "alice.pem"]);
+        assert_eq!(cli.command, Command::Keygen { out: PathBuf::from("alice.pem"), force: false });
+        let cli = parse(&["keygen", "--out", "alice.pem", "--force"]);
+        assert_eq!(cli.command, Command::Keygen { out: PathBuf::from("alice.pem"), force: true });
+    }
+
+    #[test]
+    fn test_keygen_writes_key_for_printed_address() {
+        let path = std::env::temp_dir().join(format!("shitcoin-{}-keygen.pem", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let args = ["shitcoin", "keygen", "--out", path.to_str().unwrap()];
+        let mut printed = Vec::new();
+        run(Cli::try_parse_from(args).unwrap(), &mut printed).unwrap();
+        let key = wallet::load_private_key(&path).unwrap();
+        let address = Address::from_public_key(&RsaPublicKey::from(&key));
+        assert_eq!(String::from_utf8(printed).unwrap(), format!("{}\n", address));
+
+        // a second run must not replace the key unless forced
+        let error = run(Cli::try_parse_from(args).unwrap(), &mut Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("--force"));
+        assert_eq!(wallet::load_private_key(&path).unwrap(), key);
+        let forced = ["shitcoin", "keygen", "--out", path.to_str().unwrap(), "--force"];
+        run(Cli::try_parse_from(forced).unwrap(), &mut Vec::new()).unwrap();
+        assert_ne!(wallet::load_private_key(&path).unwrap(), key);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mine_drops_transactions_that_overspend_together() {
+        let dir = std::env::temp_dir().join(format!("shitcoin-{}-mine", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let run_args = |args: &[&str]| {
+            let files = ["--chain", &path("chain.json"), "--mempool", &path("mempool.json"), "--network", "testnet"];
+            let args = std::iter::once("shitcoin").chain(files).chain(args.iter().copied());
+            let mut printed = Vec::new();
+            run(Cli::try_parse_from(args).unwrap(), &mut printed).map(|()| String::from_utf8(printed).unwrap())
+        };
+        run_args(&["keygen", "--out", &path("alice.pem")]).unwrap();
+        run_args(&["keygen", "--out", &path("bob.pem")]).unwrap();
+        run_args(&["mine", "--key", &path("alice.pem")]).unwrap();
+
+        // each is affordable on its own, but not both
+        let amount = blockchain::format_amount(blockchain::BLOCK_REWARD * 3 / 5);
+        for _ in 0..2 {
+            run_args(&["send", "--to", &path("bob.pem"), "--amount", &amount, "--key", &path("alice.pem")]).unwrap();
+        }
+        let printed = run_args(&["mine", "--key", &path("alice.pem")]).unwrap();
+        assert!(printed.contains("Dropped transaction"));
+        assert!(printed.contains("with 1 transactions"));
+        assert!(load_mempool(&dir.join("mempool.json")).unwrap().is_empty());
+        // and nothing's left over to fail the next block
+        run_args(&["mine", "--key", &path("alice.pem")]).unwrap();
+        let chain = load_chain(&dir.join("chain.json"), Network::Testnet).unwrap();
+        assert_eq!(chain.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_balance() {
+        let cli = parse(&["balance", "--key", "alice.pem"]);
+        assert_eq!(cli.command, Command::Balance { key: PathBuf::from("alice.pem") });
+    }
 }
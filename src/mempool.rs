@@ -0,0 +1,304 @@
+use crate::blockchain::{self, Blockchain, RejectReason, Transaction};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+  DuplicateUid(u64),
+  Rejected(RejectReason),
+  Full,
+}
+
+impl fmt::Display for MempoolError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MempoolError::DuplicateUid(uid) => write!(f, "a transaction with uid {} is already pending", uid),
+      MempoolError::Rejected(reason) => write!(f, "chain won't accept transaction: {}", reason),
+      MempoolError::Full => write!(f, "mempool is full of transactions paying at least as much"),
+    }
+  }
+}
+
+impl std::error::Error for MempoolError {}
+
+// how many transactions a mempool holds unless told otherwise
+pub const DEFAULT_MAX_SIZE: usize = 10_000;
+
+fn default_max_size() -> usize {
+  DEFAULT_MAX_SIZE
+}
+
+// how long the auto-miner keeps a transaction that hasn't made it into a block
+pub const DEFAULT_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+// stored as the transaction with its arrival time alongside, so mempools saved before there
+// was one still load; their transactions count as arriving at load
+#[derive(Serialize, Deserialize)]
+struct Entry {
+  #[serde(flatten)]
+  transaction: Transaction,
+  #[serde(default = "blockchain::unix_time")]
+  added_at: u64,
+}
+
+// transactions waiting to be mined into a block
+#[derive(Serialize, Deserialize)]
+pub struct Mempool {
+  transactions: Vec<Entry>,
+  // bounded so nobody can exhaust our memory with valid but pointless transactions
+  #[serde(default = "default_max_size")]
+  max_size: usize,
+}
+
+impl Default for Mempool {
+  fn default() -> Self {
+    Mempool::new()
+  }
+}
+
+impl Mempool {
+  pub fn new() -> Self {
+    Mempool::with_max_size(DEFAULT_MAX_SIZE)
+  }
+
+  pub fn with_max_size(max_size: usize) -> Self {
+    Mempool { transactions: Vec::new(), max_size }
+  }
+
+  // only what's particular to the mempool is checked here, so this is kept to the crate for
+  // transactions already known good; anything from outside comes in through add_checked
+  pub(crate) fn add(&mut self, transaction: Transaction) -> Result<(), MempoolError> {
+    self.add_at(transaction, blockchain::unix_time())
+  }
+
+  // as add, but arriving at added_at, so a transaction drained and put back keeps its place
+  // in line for expiry
+  pub(crate) fn add_at(&mut self, transaction: Transaction, added_at: u64) -> Result<(), MempoolError> {
+    if self.transactions.iter().any(|pending| pending.transaction.uid() == transaction.uid()) {
+      return Err(MempoolError::DuplicateUid(transaction.uid()));
+    }
+    if self.transactions.len() >= self.max_size {
+      // of the cheapest transactions the newest goes first, same as drain_for_block leaves it for last
+      let cheapest = self.transactions.iter().enumerate().min_by_key(|(index, pending)| (pending.transaction.fee(), std::cmp::Reverse(*index)));
+      match cheapest {
+        Some((index, pending)) if pending.transaction.fee() < transaction.fee() => {
+          self.transactions.remove(index);
+        }
+        _ => return Err(MempoolError::Full),
+      }
+    }
+    self.transactions.push(Entry { transaction, added_at });
+    Ok(())
+  }
+
+  // add only if the transaction could go in chain's next block as things stand
+  pub fn add_checked(&mut self, transaction: Transaction, chain: &Blockchain) -> Result<(), MempoolError> {
+    chain.can_accept(&transaction).map_err(MempoolError::Rejected)?;
+    self.add(transaction)
+  }
+
+  // removes and returns up to `max` transactions, highest fee first
+  pub fn drain_for_block(&mut self, max: usize) -> Vec<Transaction> {
+    self.drain_with_times(max).into_iter().map(|(transaction, _)| transaction).collect()
+  }
+
+  // as drain_for_block, with when each transaction arrived for add_at
+  pub fn drain_with_times(&mut self, max: usize) -> Vec<(Transaction, u64)> {
+    // sort_by_key is stable, so arrival order survives between equal fees
+    self.transactions.sort_by_key(|entry| std::cmp::Reverse(entry.transaction.fee()));
+    let count = max.min(self.transactions.len());
+    self.transactions.drain(..count).map(|entry| (entry.transaction, entry.added_at)).collect()
+  }
+
+  // drops every transaction that arrived more than max_age_secs ago, returning how many went
+  pub fn expire(&mut self, max_age_secs: u64) -> usize {
+    let cutoff = blockchain::unix_time().saturating_sub(max_age_secs);
+    let before = self.transactions.len();
+    self.transactions.retain(|entry| entry.added_at >= cutoff);
+    before - self.transactions.len()
+  }
+
+  pub fn len(&self) -> usize {
+    self.transactions.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.transactions.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blockchain::{generate_key_pair, sentinel_key, COIN};
+  use rsa::RsaPrivateKey;
+
+  #[test]
+  fn test_add_rejects_duplicate_uid() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut mempool = Mempool::new();
+    let first = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 1);
+    let second = Transaction::new(public_key, private_key, public_key2, 20 * COIN, 1);
+    assert_eq!(mempool.add(first), Ok(()));
+    assert_eq!(mempool.add(second), Err(MempoolError::DuplicateUid(1)));
+    assert_eq!(mempool.len(), 1);
+  }
+
+  // a funded chain to check against, since add_checked turns away anything the sender can't cover
+  fn funded_chain(miner: &RsaPrivateKey) -> Blockchain {
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), miner).unwrap();
+    chain
+  }
+
+  #[test]
+  fn test_add_checked_rejects_unsigned() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let chain = funded_chain(&private_key);
+    let mut mempool = Mempool::new();
+    let forged = Transaction::new(public_key, private_key2, public_key2, 10 * COIN, 1);
+    assert_eq!(mempool.add_checked(forged, &chain), Err(MempoolError::Rejected(RejectReason::InvalidSignature)));
+    assert!(mempool.is_empty());
+  }
+
+  #[test]
+  fn test_add_checked_rejects_sentinel_key() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let chain = funded_chain(&private_key);
+    let mut mempool = Mempool::new();
+    let sentinel = Err(MempoolError::Rejected(RejectReason::SentinelKey));
+    let burn = Transaction::new(public_key.clone(), private_key.clone(), sentinel_key(), 10 * COIN, 1);
+    assert_eq!(mempool.add_checked(burn, &chain), sentinel);
+    let outputs = vec![(public_key2.clone(), 10 * COIN), (sentinel_key(), COIN)];
+    let hidden_burn = Transaction::with_outputs(public_key.clone(), private_key, outputs, 2);
+    assert_eq!(mempool.add_checked(hidden_burn, &chain), sentinel);
+    assert_eq!(mempool.add_checked(Transaction::coinbase(public_key2, 50 * COIN, 3), &chain), sentinel);
+    assert!(mempool.is_empty());
+  }
+
+  #[test]
+  fn test_add_checked_rejects_zero_amounts() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let chain = funded_chain(&private_key);
+    let mut mempool = Mempool::new();
+    let zero = Err(MempoolError::Rejected(RejectReason::ZeroAmount));
+    let nothing = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 0, 1);
+    assert_eq!(mempool.add_checked(nothing, &chain), zero);
+    let outputs = vec![(public_key2.clone(), COIN), (public_key.clone(), 0)];
+    let empty_output = Transaction::with_outputs(public_key.clone(), private_key.clone(), outputs, 2);
+    assert_eq!(mempool.add_checked(empty_output, &chain), zero);
+    // the fee can be zero, it's only the outputs that have to carry something
+    let free = Transaction::with_fee(public_key, private_key, public_key2, 1, 0, 3);
+    assert_eq!(mempool.add_checked(free, &chain), Ok(()));
+  }
+
+  #[test]
+  fn test_add_checked_consults_chain() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    let mut mempool = Mempool::new();
+    let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2, 10 * COIN, 1);
+    assert_eq!(mempool.add_checked(payment.clone(), &chain), Err(MempoolError::Rejected(RejectReason::InsufficientFunds)));
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    assert_eq!(mempool.add_checked(payment, &chain), Ok(()));
+    assert_eq!(mempool.len(), 1);
+  }
+
+  #[test]
+  fn test_full_mempool_evicts_lowest_fee() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut mempool = Mempool::with_max_size(3);
+    for (uid, fee) in [(0, 2 * COIN), (1, COIN), (2, 3 * COIN)] {
+      mempool.add(Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, fee, uid)).unwrap();
+    }
+    // paying no more than the cheapest isn't enough to get in
+    let cheap = Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, COIN, 3);
+    assert_eq!(mempool.add(cheap), Err(MempoolError::Full));
+    let generous = Transaction::with_fee(public_key, private_key, public_key2, COIN, 5 * COIN, 4);
+    assert_eq!(mempool.add(generous), Ok(()));
+    assert_eq!(mempool.len(), 3);
+    let uids: Vec<u64> = mempool.drain_for_block(10).iter().map(Transaction::uid).collect();
+    assert_eq!(uids, vec![4, 2, 0]);
+  }
+
+  #[test]
+  fn test_expire_drops_old_transactions() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut mempool = Mempool::new();
+    let old = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 1);
+    mempool.add_at(old, blockchain::unix_time() - 2 * 60 * 60).unwrap();
+    mempool.add(Transaction::new(public_key, private_key, public_key2, COIN, 2)).unwrap();
+    assert_eq!(mempool.expire(60 * 60), 1);
+    assert_eq!(mempool.expire(60 * 60), 0);
+    assert_eq!(mempool.drain_for_block(10)[0].uid(), 2);
+  }
+
+  #[test]
+  fn test_drained_transactions_keep_arrival_time() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut mempool = Mempool::new();
+    let old = Transaction::new(public_key, private_key, public_key2, COIN, 1);
+    let added_at = blockchain::unix_time() - 2 * 60 * 60;
+    mempool.add_at(old.clone(), added_at).unwrap();
+    let drained = mempool.drain_with_times(10);
+    assert_eq!(drained, vec![(old, added_at)]);
+    for (transaction, added_at) in drained {
+      mempool.add_at(transaction, added_at).unwrap();
+    }
+    // still as old as when it first arrived
+    assert_eq!(mempool.expire(60 * 60), 1);
+  }
+
+  #[test]
+  fn test_saved_mempool_round_trips() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(public_key, private_key, public_key2, COIN, 1);
+    let mut mempool = Mempool::new();
+    mempool.add_at(transaction.clone(), 1000).unwrap();
+    let mut loaded: Mempool = serde_json::from_str(&serde_json::to_string(&mempool).unwrap()).unwrap();
+    assert_eq!(loaded.transactions[0].added_at, 1000);
+    assert_eq!(loaded.drain_for_block(1), vec![transaction.clone()]);
+
+    // from before entries had a time
+    let json = format!("{{\"transactions\":[{}]}}", serde_json::to_string(&transaction).unwrap());
+    let loaded: Mempool = serde_json::from_str(&json).unwrap();
+    assert!(loaded.transactions[0].added_at >= blockchain::unix_time() - 60);
+  }
+
+  #[test]
+  fn test_drain_for_block() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut mempool = Mempool::new();
+    for uid in 0..3 {
+      mempool.add(Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, uid)).unwrap();
+    }
+    let drained = mempool.drain_for_block(2);
+    assert_eq!(drained.iter().map(Transaction::uid).collect::<Vec<u64>>(), vec![0, 1]);
+    assert_eq!(mempool.len(), 1);
+    assert_eq!(mempool.drain_for_block(10).len(), 1);
+    assert!(mempool.is_empty());
+  }
+
+  #[test]
+  fn test_drain_for_block_prefers_higher_fees() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut mempool = Mempool::new();
+    mempool.add(Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, COIN / 10, 0)).unwrap();
+    mempool.add(Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, 2 * COIN, 1)).unwrap();
+    mempool.add(Transaction::with_fee(public_key, private_key, public_key2, 10 * COIN, COIN / 10, 2)).unwrap();
+    let drained = mempool.drain_for_block(2);
+    assert_eq!(drained.iter().map(Transaction::uid).collect::<Vec<u64>>(), vec![1, 0]);
+    assert_eq!(mempool.drain_for_block(1)[0].uid(), 2);
+  }
+}
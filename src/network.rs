@@ -1,14 +1,26 @@
 use std::thread;
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use std::io::{self, Read, Write};
 use std::str::from_utf8;
+use std::convert::TryFrom;
+use serde::{Serialize, Deserialize};
+use crate::blockchain::{Block, BlockError, BlockHeader, Blockchain, ReorgError, SHA256Hash, Transaction, ValidationError};
+use crate::mempool::{self, Mempool, MempoolError};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use log::{info, warn};
 
 fn handle_client(mut stream: TcpStream) {
-    let mut data = [0 as u8; 50]; // using 50 byte buffer
+    let mut data = [0_u8; 50]; // using 50 byte buffer
     while match stream.read(&mut data) {
+        // the client hung up
+        Ok(0) => false,
         Ok(size) => {
             // echo everything!
-            stream.write(&data[0..size]).unwrap();
+            stream.write_all(&data[0..size]).unwrap();
             true
         },
         Err(_) => {
@@ -19,27 +31,42 @@ fn handle_client(mut stream: TcpStream) {
     } {}
 }
 
-pub fn server() {
-    let listener = TcpListener::bind("0.0.0.0:3333").unwrap();
-    // accept connections and process them, spawning a new thread for each one
-    println!("Server listening on port 3333");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("New connection: {}", stream.peer_addr().unwrap());
-                thread::spawn(move|| {
-                    // connection succeeded
-                    handle_client(stream)
-                });
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-                /* connection failed */
-            }
+// how long an accept loop sleeps between checks for new connections or shutdown
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+// yields connections on listener until shutdown is set
+fn incoming_until<'a>(listener: &'a TcpListener, shutdown: &'a AtomicBool) -> io::Result<impl Iterator<Item = TcpStream> + 'a> {
+    listener.set_nonblocking(true)?;
+    Ok(std::iter::from_fn(move || loop {
+        if shutdown.load(Ordering::SeqCst) {
+            return None;
         }
+        match listener.accept() {
+            // some platforms hand the listener's non blocking mode down to its connections
+            Ok((stream, _)) if stream.set_nonblocking(false).is_ok() => return Some(stream),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(e) => println!("Error: {}", e),
+        }
+    }))
+}
+
+// echoes on listener until shutdown is set, then waits for open connections to close
+pub fn server(listener: TcpListener, shutdown: Arc<AtomicBool>) -> io::Result<()> {
+    // accept connections and process them, spawning a new thread for each one
+    println!("Server listening on {}", listener.local_addr()?);
+    let mut handlers = Vec::new();
+    for stream in incoming_until(&listener, &shutdown)? {
+        println!("New connection: {}", stream.peer_addr()?);
+        handlers.push(thread::spawn(move|| {
+            // connection succeeded
+            handle_client(stream)
+        }));
+    }
+    for handler in handlers {
+        let _ = handler.join();
     }
-    // close the socket server
-    drop(listener);
+    Ok(())
 }
 
 pub fn client() {
@@ -49,10 +76,10 @@ pub fn client() {
 
             let msg = b"Hello!";
 
-            stream.write(msg).unwrap();
+            stream.write_all(msg).unwrap();
             println!("Sent Hello, awaiting reply...");
 
-            let mut data = [0 as u8; 6]; // using 6 byte buffer
+            let mut data = [0_u8; 6]; // using 6 byte buffer
             match stream.read_exact(&mut data) {
                 Ok(_) => {
                     if &data == msg {
@@ -72,4 +99,1727 @@ pub fn client() {
         }
     }
     println!("Terminated.");
-}
\ No newline at end of file
+}
+
+// the largest payload a peer may send, checked before anything is allocated for it
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+// replies with blocks or headers stop at this many, and whoever asked keeps asking from
+// where the last one left off until they've caught up
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 100;
+
+// encoded blocks go out as JSON numbers of up to four characters a byte, so a reply whose
+// blocks come to no more than this stays under MAX_MESSAGE_SIZE
+const MAX_BLOCK_BYTES_PER_MESSAGE: usize = MAX_MESSAGE_SIZE / 5;
+
+// every message is a 4 byte big endian length followed by the payload
+pub fn write_message(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|_| payload.len() <= MAX_MESSAGE_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+    stream.write_all(&len.to_be_bytes()).map_err(timed_out)?;
+    stream.write_all(payload).map_err(timed_out)?;
+    stream.flush().map_err(timed_out)
+}
+
+pub fn read_message(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0_u8; 4];
+    stream.read_exact(&mut len).map_err(timed_out)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("peer sent a {} byte message", len)));
+    }
+    let mut payload = vec![0_u8; len];
+    stream.read_exact(&mut payload).map_err(timed_out)?;
+    Ok(payload)
+}
+
+// an expired socket timeout is always reported as TimedOut, though unix says WouldBlock
+fn timed_out(error: io::Error) -> io::Error {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(io::ErrorKind::TimedOut, "peer stopped responding"),
+        _ => error,
+    }
+}
+
+// how long a peer may stall a read or write before it's dropped
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn set_timeouts(stream: &TcpStream, timeout: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))
+}
+
+// a block encoded for the wire, decoded only once the receiver wants it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SerializedBlock(Vec<u8>);
+
+impl SerializedBlock {
+    pub fn from_block(block: &Block) -> SerializedBlock {
+        SerializedBlock(serde_json::to_vec(block).expect("blocks always serialize"))
+    }
+
+    pub fn to_block(&self) -> serde_json::Result<Block> {
+        serde_json::from_slice(&self.0)
+    }
+}
+
+// the leading blocks that fit in one reply, always at least one so a sync can make progress
+fn page_of_blocks<'a>(blocks: impl IntoIterator<Item = &'a Block>) -> Vec<SerializedBlock> {
+    let mut page = Vec::new();
+    let mut bytes = 0;
+    for block in blocks.into_iter().take(MAX_BLOCKS_PER_MESSAGE) {
+        let block = SerializedBlock::from_block(block);
+        bytes += block.0.len();
+        if bytes > MAX_BLOCK_BYTES_PER_MESSAGE && !page.is_empty() {
+            break;
+        }
+        page.push(block);
+    }
+    page
+}
+
+// messages are built, sent and dropped, so the size of Tx doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NetMessage {
+    Tx(Transaction),
+    Block(SerializedBlock),
+    GetBlocks { from_height: u64 },
+    Blocks(Vec<SerializedBlock>),
+    GetHeaders { from_height: u64 },
+    Headers(Vec<BlockHeader>),
+    // asks for the full blocks with these hashes, answered with Blocks in the same order
+    GetBodies { hashes: Vec<SHA256Hash> },
+    Ping,
+    Pong,
+    // listen_port is where the sender accepts connections, if anywhere
+    Version {
+        protocol_version: u32,
+        chain_height: u64,
+        #[serde(default)]
+        listen_port: Option<u16>,
+    },
+}
+
+impl NetMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("messages always serialize")
+    }
+
+    pub fn decode(bytes: &[u8]) -> serde_json::Result<NetMessage> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// height of the tip, the genesis block is height 0
+fn chain_height(chain: &Blockchain) -> u64 {
+    chain.len() as u64 - 1
+}
+
+// both sides send their version first and then wait for the other's, returning the
+// peer's chain height and listen port if it speaks our protocol
+pub fn handshake(stream: &mut TcpStream, chain_height: u64, listen_port: Option<u16>) -> io::Result<(u64, Option<u16>)> {
+    send_message(stream, &NetMessage::Version { protocol_version: PROTOCOL_VERSION, chain_height, listen_port })?;
+    match receive_message(stream)? {
+        NetMessage::Version { protocol_version, chain_height, listen_port } if protocol_version == PROTOCOL_VERSION => Ok((chain_height, listen_port)),
+        NetMessage::Version { protocol_version, .. } => {
+            let _ = stream.shutdown(Shutdown::Both);
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("peer speaks protocol version {}", protocol_version)))
+        }
+        _ => {
+            let _ = stream.shutdown(Shutdown::Both);
+            Err(io::Error::new(io::ErrorKind::InvalidData, "peer didn't start with a version message"))
+        }
+    }
+}
+
+pub fn send_message(stream: &mut impl Write, message: &NetMessage) -> io::Result<()> {
+    write_message(stream, &message.encode())
+}
+
+pub fn receive_message(stream: &mut impl Read) -> io::Result<NetMessage> {
+    let payload = read_message(stream)?;
+    NetMessage::decode(&payload).map_err(io::Error::from)
+}
+
+// the writing half of a peer connection, locked for each message so ones sent from
+// different threads never interleave
+pub type PeerStream = Arc<Mutex<TcpStream>>;
+
+// the peers we're connected to, at most one connection per address; a peer's address is
+// where it accepts connections, whichever side dialed
+#[derive(Default)]
+pub struct PeerSet {
+    peers: HashMap<SocketAddr, PeerStream>,
+}
+
+impl PeerSet {
+    pub fn new() -> PeerSet {
+        PeerSet { peers: HashMap::new() }
+    }
+
+    // returns false and drops stream if addr is already connected
+    pub fn add(&mut self, addr: SocketAddr, stream: TcpStream) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        self.peers.insert(addr, Arc::new(Mutex::new(stream)));
+        true
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<PeerStream> {
+        self.peers.get(addr).cloned()
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<PeerStream> {
+        self.peers.remove(addr)
+    }
+
+    // removes addr only if it's still connected over stream, not a connection made since
+    pub fn remove_stream(&mut self, addr: &SocketAddr, stream: &PeerStream) -> bool {
+        if !self.peers.get(addr).is_some_and(|current| Arc::ptr_eq(current, stream)) {
+            return false;
+        }
+        self.peers.remove(addr);
+        true
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &PeerStream)> {
+        self.peers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+// how a node catches up with a taller peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    // take every block the peer has past our tip
+    #[default]
+    Blocks,
+    // check the headers' work and links first, then only download bodies for the ones that hold up
+    HeadersFirst,
+}
+
+// state shared between a node and the threads serving its peers
+struct Shared {
+    chain: Mutex<Blockchain>,
+    mempool: Mutex<Mempool>,
+    peers: Mutex<PeerSet>,
+    // where this node accepts peers, once it does
+    listen_port: Mutex<Option<u16>>,
+    // hashes of transactions already relayed, so gossip doesn't loop
+    seen: Mutex<SeenSet>,
+    shutdown: AtomicBool,
+    timeout: Duration,
+    // hashes trusted ahead of time; no block at one of these heights is taken unless it matches
+    checkpoints: Mutex<HashMap<u64, SHA256Hash>>,
+    // set whenever the tip moves, so a block being mined on the old one is given up
+    tip_changed: AtomicBool,
+    // hashes of every block with valid work we've seen, by the block they build on
+    children: Mutex<HashMap<SHA256Hash, Vec<SHA256Hash>>>,
+    sync_mode: Mutex<SyncMode>,
+    // blocks whose parent we haven't seen yet, by that parent's hash, until it turns up
+    orphans: Mutex<HashMap<SHA256Hash, Vec<Block>>>,
+}
+
+// how long the auto miner waits before looking at an empty mempool again
+const MINING_IDLE: Duration = Duration::from_millis(50);
+
+// orphans past this many are dropped, since their work is all that vouches for them
+const MAX_ORPHANS: usize = 100;
+
+// twice what a full mempool holds, so a transaction still waiting to be mined isn't forgotten
+const MAX_SEEN: usize = 2 * mempool::DEFAULT_MAX_SIZE;
+
+// transaction hashes in the order they were first seen, forgetting the oldest past MAX_SEEN;
+// a forgotten transaction that comes round again is just checked again
+#[derive(Default)]
+struct SeenSet {
+    hashes: HashSet<SHA256Hash>,
+    order: VecDeque<SHA256Hash>,
+}
+
+impl SeenSet {
+    fn contains(&self, hash: &SHA256Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    fn insert(&mut self, hash: SHA256Hash) {
+        if !self.hashes.insert(hash) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_SEEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.hashes.len()
+    }
+}
+
+fn contradicts(checkpoints: &HashMap<u64, SHA256Hash>, height: u64, hash: &SHA256Hash) -> bool {
+    checkpoints.get(&height).is_some_and(|checkpoint| checkpoint != hash)
+}
+
+impl Shared {
+    fn send_to_peers(&self, message: &NetMessage, except: Option<SocketAddr>) {
+        let payload = message.encode();
+        // written to without holding the peer set, so a slow peer doesn't hold up everyone else using it
+        let peers: Vec<(SocketAddr, PeerStream)> = self.peers.lock().unwrap()
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != except)
+            .map(|(addr, peer)| (*addr, Arc::clone(peer)))
+            .collect();
+        let mut failed = Vec::new();
+        for (addr, peer) in peers {
+            // a peer that went away or stopped reading shouldn't stop the others from
+            // hearing about it, and isn't worth keeping
+            let written = write_message(&mut *peer.lock().unwrap(), &payload);
+            if let Err(error) = written {
+                failed.push((addr, peer, error));
+            }
+        }
+        let mut peers = self.peers.lock().unwrap();
+        for (addr, peer, error) in failed {
+            warn!("dropping peer {}, writing to it failed: {}", addr, error);
+            peers.remove_stream(&addr, &peer);
+        }
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        *self.listen_port.lock().unwrap()
+    }
+
+    // None if a connection to addr is already open, and this one should be dropped
+    fn register_peer(&self, addr: SocketAddr, stream: &TcpStream) -> io::Result<Option<PeerStream>> {
+        let mut peers = self.peers.lock().unwrap();
+        Ok(if peers.add(addr, stream.try_clone()?) { peers.get(&addr) } else { None })
+    }
+
+    // remembered even if it never makes it into our chain, that's how forks get noticed;
+    // checking the work first means made-up blocks can't fill the map for free
+    fn record_block(&self, block: &Block) {
+        if !block.verify_pow() {
+            return;
+        }
+        let mut children = self.children.lock().unwrap();
+        let siblings = children.entry(*block.previous_hash()).or_default();
+        if !siblings.contains(block.hash()) {
+            siblings.push(*block.hash());
+        }
+    }
+
+    fn append_block(&self, block: Block) {
+        self.record_block(&block);
+        let mut chain = self.chain.lock().unwrap();
+        let hash = *block.hash();
+        info!("received block {}", hash);
+        // an orphan's height isn't known yet, connect_orphans checks it once it is
+        if !chain.blocks().any(|known| known.hash() == block.previous_hash()) {
+            self.hold_orphan(block);
+            return;
+        }
+        if contradicts(&self.checkpoints.lock().unwrap(), chain.len() as u64, &hash) {
+            warn!("block {} rejected: it contradicts a checkpoint", hash);
+            return;
+        }
+        // blocks that don't extend our chain are dropped
+        match chain.append_block(block) {
+            Ok(()) => {
+                info!("block {} validated at height {}", hash, chain.len() - 1);
+                self.connect_orphans(&mut chain);
+                self.tip_changed.store(true, Ordering::SeqCst);
+            }
+            Err(error) => warn!("block {} rejected: {}", hash, error),
+        }
+    }
+
+    fn hold_orphan(&self, block: Block) {
+        let mut orphans = self.orphans.lock().unwrap();
+        if !block.verify_pow() || orphans.values().map(Vec::len).sum::<usize>() >= MAX_ORPHANS {
+            warn!("block {} rejected: its parent is unknown", block.hash());
+            return;
+        }
+        info!("holding block {} until its parent {} arrives", block.hash(), block.previous_hash());
+        orphans.entry(*block.previous_hash()).or_default().push(block);
+    }
+
+    // append whichever orphans now build on the tip, and any that build on those in turn
+    fn connect_orphans(&self, chain: &mut Blockchain) {
+        let mut orphans = self.orphans.lock().unwrap();
+        let checkpoints = self.checkpoints.lock().unwrap();
+        while let Some(children) = orphans.remove(&chain.last_hash()) {
+            // at most one child can extend the tip, the rest are forks we'd drop anyway
+            let height = chain.len() as u64;
+            let extended = children.into_iter()
+                .filter(|child| !contradicts(&checkpoints, height, child.hash()))
+                .any(|child| chain.append_block(child).is_ok());
+            if !extended {
+                break;
+            }
+            info!("connected orphan block {} at height {}", chain.last_hash(), height);
+        }
+    }
+
+    // apply blocks in order on top of our tip, returning how many were appended before
+    // one didn't decode or was rejected
+    fn append_blocks(&self, blocks: &[SerializedBlock]) -> usize {
+        self.append_decoded_blocks(blocks.iter().map_while(|block| block.to_block().ok()).collect())
+    }
+
+    fn append_decoded_blocks(&self, blocks: Vec<Block>) -> usize {
+        for block in &blocks {
+            self.record_block(block);
+        }
+        let mut chain = self.chain.lock().unwrap();
+        let start = chain.len() as u64;
+        // disagreeing with a checkpoint anywhere means it's a chain we'll never follow
+        let checkpoints = self.checkpoints.lock().unwrap();
+        if blocks.iter().zip(start..).any(|(block, height)| contradicts(&checkpoints, height, block.hash())) {
+            warn!("{} blocks from height {} rejected: they contradict a checkpoint", blocks.len(), start);
+            return 0;
+        }
+        info!("received {} blocks from height {}", blocks.len(), start);
+        let mut appended = 0;
+        for block in blocks {
+            let hash = *block.hash();
+            if let Err(error) = chain.append_block(block) {
+                warn!("block {} rejected: {}", hash, error);
+                break;
+            }
+            appended += 1;
+        }
+        if appended > 0 {
+            drop(checkpoints);
+            self.connect_orphans(&mut chain);
+            info!("validated blocks up to height {}", chain.len() - 1);
+            self.tip_changed.store(true, Ordering::SeqCst);
+        }
+        appended
+    }
+
+    // only the first sighting of a valid transaction gets relayed, to everyone but who sent it
+    fn gossip_transaction(&self, transaction: Transaction, from: Option<SocketAddr>) -> Result<(), MempoolError> {
+        let hash = transaction.hash();
+        if self.seen.lock().unwrap().contains(&hash) {
+            return Ok(());
+        }
+        let message = NetMessage::Tx(transaction.clone());
+        let uid = transaction.uid();
+        info!("received transaction {}", uid);
+        let chain = self.chain.lock().unwrap();
+        if let Err(error) = self.mempool.lock().unwrap().add_checked(transaction, &chain) {
+            warn!("transaction {} rejected: {}", uid, error);
+            return Err(error);
+        }
+        drop(chain);
+        // only once it's in, so a copy turned away now is checked again when it next turns up
+        self.seen.lock().unwrap().insert(hash);
+        self.send_to_peers(&message, from);
+        Ok(())
+    }
+}
+
+// a point in time view of a node, for monitoring
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeMetrics {
+    pub chain_height: u64,
+    pub mempool_size: usize,
+    pub peer_count: usize,
+    pub total_transactions: usize,
+    // what the next block has to be mined at
+    pub difficulty: u32,
+}
+
+// clones are handles to the same node
+#[derive(Clone)]
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+impl Node {
+    pub fn new(chain: Blockchain) -> Node {
+        Node::with_timeout(chain, DEFAULT_TIMEOUT)
+    }
+
+    // timeout applies to every read and write on a peer connection
+    pub fn with_timeout(chain: Blockchain, timeout: Duration) -> Node {
+        let mut children: HashMap<SHA256Hash, Vec<SHA256Hash>> = HashMap::new();
+        for block in chain.blocks().skip(1) {
+            children.entry(*block.previous_hash()).or_default().push(*block.hash());
+        }
+        let shared = Shared {
+            chain: Mutex::new(chain),
+            mempool: Mutex::new(Mempool::new()),
+            peers: Mutex::new(PeerSet::new()),
+            listen_port: Mutex::new(None),
+            seen: Mutex::new(SeenSet::default()),
+            shutdown: AtomicBool::new(false),
+            timeout,
+            checkpoints: Mutex::new(HashMap::new()),
+            tip_changed: AtomicBool::new(false),
+            children: Mutex::new(children),
+            sync_mode: Mutex::new(SyncMode::default()),
+            orphans: Mutex::new(HashMap::new()),
+        };
+        Node { shared: Arc::new(shared) }
+    }
+
+    pub fn chain(&self) -> MutexGuard<'_, Blockchain> {
+        self.shared.chain.lock().unwrap()
+    }
+
+    pub fn mempool(&self) -> MutexGuard<'_, Mempool> {
+        self.shared.mempool.lock().unwrap()
+    }
+
+    pub fn peers(&self) -> MutexGuard<'_, PeerSet> {
+        self.shared.peers.lock().unwrap()
+    }
+
+    pub fn metrics(&self) -> NodeMetrics {
+        let (chain_height, total_transactions, difficulty) = {
+            let chain = self.chain();
+            (chain_height(&chain), chain.transaction_count(), chain.next_difficulty())
+        };
+        NodeMetrics {
+            chain_height,
+            mempool_size: self.mempool().len(),
+            peer_count: self.peers().len(),
+            total_transactions,
+            difficulty,
+        }
+    }
+
+    // connecting to a peer we already have is a no-op
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.iter().any(|addr| self.peers().contains(addr)) {
+            return Ok(());
+        }
+        let mut stream = TcpStream::connect(&addrs[..])?;
+        set_timeouts(&stream, self.shared.timeout)?;
+        let height = chain_height(&self.chain());
+        let (peer_height, _) = handshake(&mut stream, height, self.shared.listen_port())?;
+        if peer_height > height {
+            self.sync(&mut stream, height + 1)?;
+        }
+        // we dialed where the peer listens, so that's already the address it goes by
+        let peer = stream.peer_addr()?;
+        if let Some(writer) = self.shared.register_peer(peer, &stream)? {
+            info!("connected to peer {}", peer);
+            let shared = Arc::clone(&self.shared);
+            thread::spawn(move || serve_peer(&shared, stream, peer, writer));
+        }
+        Ok(())
+    }
+
+    // applies to peers connected from now on
+    pub fn set_sync_mode(&self, mode: SyncMode) {
+        *self.shared.sync_mode.lock().unwrap() = mode;
+    }
+
+    // fetch everything the peer has from from_height on and apply it in order,
+    // returning how many blocks were appended before one was rejected
+    fn sync(&self, stream: &mut TcpStream, from_height: u64) -> io::Result<usize> {
+        let mode = *self.shared.sync_mode.lock().unwrap();
+        match mode {
+            SyncMode::Blocks => self.sync_blocks(stream, from_height),
+            SyncMode::HeadersFirst => self.sync_headers_first(stream, from_height),
+        }
+    }
+
+    // a page at a time, until the peer has nothing more or one of its blocks is rejected
+    fn sync_blocks(&self, stream: &mut TcpStream, from_height: u64) -> io::Result<usize> {
+        let mut from_height = from_height;
+        let mut appended = 0;
+        loop {
+            send_message(stream, &NetMessage::GetBlocks { from_height })?;
+            let blocks = loop {
+                if let NetMessage::Blocks(blocks) = receive_message(stream)? {
+                    break blocks;
+                }
+            };
+            let count = self.shared.append_blocks(&blocks);
+            appended += count;
+            if blocks.is_empty() || count < blocks.len() {
+                return Ok(appended);
+            }
+            from_height = chain_height(&self.chain()) + 1;
+        }
+    }
+
+    fn sync_headers_first(&self, stream: &mut TcpStream, from_height: u64) -> io::Result<usize> {
+        let mut from_height = from_height;
+        let mut appended = 0;
+        loop {
+            send_message(stream, &NetMessage::GetHeaders { from_height })?;
+            let headers = loop {
+                if let NetMessage::Headers(headers) = receive_message(stream)? {
+                    break headers;
+                }
+            };
+            let hashes: Vec<SHA256Hash> = self.valid_headers(from_height, headers).iter().map(BlockHeader::hash).collect();
+            if hashes.is_empty() {
+                return Ok(appended);
+            }
+            send_message(stream, &NetMessage::GetBodies { hashes: hashes.clone() })?;
+            let bodies = loop {
+                if let NetMessage::Blocks(bodies) = receive_message(stream)? {
+                    break bodies;
+                }
+            };
+            // a body only counts if it's the block its header promised; fewer bodies than
+            // headers may come back if they don't all fit, the rest are asked for next time
+            let blocks = bodies.iter()
+                .zip(&hashes)
+                .map_while(|(body, hash)| body.to_block().ok().filter(|block| block.hash() == hash))
+                .collect();
+            let count = self.shared.append_decoded_blocks(blocks);
+            appended += count;
+            if count == 0 {
+                return Ok(appended);
+            }
+            from_height = chain_height(&self.chain()) + 1;
+        }
+    }
+
+    // the leading run of headers that links up from our block below from_height,
+    // each with the work it claims and agreeing with our checkpoints
+    fn valid_headers(&self, from_height: u64, headers: Vec<BlockHeader>) -> Vec<BlockHeader> {
+        let mut previous = match from_height.checked_sub(1).and_then(|height| self.chain().block_at(height).map(|block| *block.hash())) {
+            Some(hash) => hash,
+            None => return Vec::new(),
+        };
+        let checkpoints = self.shared.checkpoints.lock().unwrap();
+        headers.into_iter()
+            .zip(from_height..)
+            .map_while(|(header, height)| {
+                let hash = header.hash();
+                let valid = header.verify_pow() && *header.previous_hash() == previous && !contradicts(&checkpoints, height, &hash);
+                previous = hash;
+                valid.then_some(header)
+            })
+            .collect()
+    }
+
+    // mine on the local chain and tell every peer about the new block; the chain is only
+    // locked to build the template and append the result, not while searching for a nonce
+    pub fn mine_block(&self, transactions: Vec<Transaction>, miner: &RsaPrivateKey) -> Result<(), BlockError> {
+        let never = AtomicBool::new(false);
+        let block = loop {
+            let template = self.chain().block_template(transactions.clone(), RsaPublicKey::from(miner))?;
+            let Some(mut block) = template.mine(&never)? else { continue };
+            block.sign(miner);
+            self.shared.record_block(&block);
+            let mut chain = self.chain();
+            match chain.append_block(block.clone()) {
+                Ok(()) => break block,
+                // a peer's block got there first, so mine again on top of it
+                Err(_) if chain.last_hash() != *block.previous_hash() => continue,
+                Err(error) => return Err(error),
+            }
+        };
+        self.shared.tip_changed.store(true, Ordering::SeqCst);
+        self.broadcast_block(&block);
+        Ok(())
+    }
+
+    // from now on only a block with hash is taken at height, and the chain we have must agree
+    pub fn add_checkpoint(&self, height: u64, hash: SHA256Hash) -> Result<(), ValidationError> {
+        if self.chain().block_at(height).is_some_and(|block| *block.hash() != hash) {
+            return Err(ValidationError::CheckpointMismatch { index: height as usize });
+        }
+        self.shared.checkpoints.lock().unwrap().insert(height, hash);
+        Ok(())
+    }
+
+    // move to a heavier branch, putting the transactions it drops back in the mempool
+    pub fn reorg_to(&self, blocks: Vec<Block>) -> Result<(), ReorgError> {
+        let mut chain = self.chain();
+        // however much work a branch has, it can't replace a checkpointed block
+        if let Some(start) = blocks.first().and_then(|first| chain.blocks().position(|block| block.hash() == first.previous_hash())) {
+            let checkpoints = self.shared.checkpoints.lock().unwrap();
+            let contradicted = blocks.iter().zip(start as u64 + 1..).find(|(block, height)| contradicts(&checkpoints, *height, block.hash()));
+            if let Some((_, height)) = contradicted {
+                warn!("branch rejected: it contradicts the checkpoint at height {}", height);
+                return Err(ReorgError::Invalid(BlockError::Invalid(ValidationError::CheckpointMismatch { index: height as usize })));
+            }
+        }
+        for block in &blocks {
+            self.shared.record_block(block);
+        }
+        let abandoned = chain.reorg_to(blocks)?;
+        self.shared.tip_changed.store(true, Ordering::SeqCst);
+        let mut mempool = self.mempool();
+        for transaction in abandoned {
+            // the new branch may already spend what they did, so they're checked against it
+            let _ = mempool.add_checked(transaction, &chain);
+        }
+        Ok(())
+    }
+
+    // every block we know of that more than one valid block builds on, with those children
+    pub fn detect_forks(&self) -> Vec<(SHA256Hash, Vec<SHA256Hash>)> {
+        let children = self.shared.children.lock().unwrap();
+        let mut forks: Vec<(SHA256Hash, Vec<SHA256Hash>)> = children
+            .iter()
+            .filter(|(_, siblings)| siblings.len() > 1)
+            .map(|(parent, siblings)| (*parent, siblings.clone()))
+            .collect();
+        forks.sort();
+        forks
+    }
+
+    pub fn broadcast_block(&self, block: &Block) {
+        self.shared.send_to_peers(&NetMessage::Block(SerializedBlock::from_block(block)), None);
+    }
+
+    // keep the transaction for our own next block and pass it on to peers
+    pub fn broadcast_transaction(&self, transaction: Transaction) -> Result<(), MempoolError> {
+        self.shared.gossip_transaction(transaction, None)
+    }
+
+    // accept peers on listener in the background, one thread per connection,
+    // until shutdown is called
+    pub fn listen(&self, listener: TcpListener) -> thread::JoinHandle<()> {
+        *self.shared.listen_port.lock().unwrap() = listener.local_addr().ok().map(|addr| addr.port());
+        let shared = Arc::clone(&self.shared);
+        thread::spawn(move || {
+            let incoming = match incoming_until(&listener, &shared.shutdown) {
+                Ok(incoming) => incoming,
+                Err(_) => return,
+            };
+            for stream in incoming {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || handle_peer(&shared, stream));
+            }
+        })
+    }
+
+    // connections on listener until shutdown is called, with this node's timeouts applied
+    pub(crate) fn incoming<'a>(&'a self, listener: &'a TcpListener) -> io::Result<impl Iterator<Item = TcpStream> + 'a> {
+        let timeout = self.shared.timeout;
+        let incoming = incoming_until(listener, &self.shared.shutdown)?;
+        Ok(incoming.filter(move |stream| set_timeouts(stream, timeout).is_ok()))
+    }
+
+    // stops every listener of this node from accepting new peers
+    pub fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.tip_changed.store(true, Ordering::SeqCst);
+    }
+
+    // mine blocks from the mempool in the background, paying miner, until shutdown is called
+    pub fn start_mining(&self, miner: RsaPrivateKey) -> thread::JoinHandle<()> {
+        let node = self.clone();
+        thread::spawn(move || {
+            while !node.shared.shutdown.load(Ordering::SeqCst) {
+                let expired = node.mempool().expire(mempool::DEFAULT_MAX_AGE_SECS);
+                if expired > 0 {
+                    info!("dropped {} transactions that waited too long to be mined", expired);
+                }
+                if !node.mine_from_mempool(&miner) {
+                    thread::sleep(MINING_IDLE);
+                }
+            }
+        })
+    }
+
+    // mine one block from the mempool without holding the chain while searching for the nonce,
+    // returning false if there was nothing it could mine
+    fn mine_from_mempool(&self, miner: &RsaPrivateKey) -> bool {
+        let max_transactions = self.chain().params().max_block_txs;
+        let mut drained = self.mempool().drain_with_times(max_transactions);
+        if drained.is_empty() {
+            return false;
+        }
+        self.shared.tip_changed.store(false, Ordering::SeqCst);
+        let template = loop {
+            let transactions = drained.iter().map(|(transaction, _)| transaction.clone()).collect();
+            match self.chain().block_template(transactions, RsaPublicKey::from(miner)) {
+                Ok(template) => break template,
+                Err(error) => match error.unminable_uid() {
+                    // transactions the chain has moved past can never be mined, so they're dropped
+                    Some(uid) => drained.retain(|(transaction, _)| transaction.uid() != uid),
+                    // not one transaction's fault, so they all wait for another try
+                    None => {
+                        warn!("couldn't build a block from {} transactions: {}", drained.len(), error);
+                        self.restore_to_mempool(drained);
+                        return false;
+                    }
+                },
+            }
+            if drained.is_empty() {
+                return true;
+            }
+        };
+        let mined = match template.mine(&self.shared.tip_changed) {
+            Ok(Some(mut block)) => {
+                block.sign(miner);
+                self.shared.record_block(&block);
+                let mut chain = self.chain();
+                let appended = chain.append_block(block.clone());
+                let height = chain_height(&chain);
+                drop(chain);
+                if appended.is_ok() {
+                    info!("block mined at height {} with nonce {}", height, block.nonce());
+                    self.broadcast_block(&block);
+                    return true;
+                }
+                appended
+            }
+            // stopped early for a new tip
+            Ok(None) => Ok(()),
+            Err(error) => Err(BlockError::from(error)),
+        };
+        self.restore_to_mempool(drained);
+        // someone else's block got there first, so try again on top of it
+        if self.shared.tip_changed.load(Ordering::SeqCst) {
+            return true;
+        }
+        // our own block is at fault, and mining it again straight away would fail the same way
+        if let Err(error) = mined {
+            warn!("mined block rejected: {}", error);
+            return false;
+        }
+        true
+    }
+
+    // put back transactions drained for a block that wasn't mined, as old as they were
+    fn restore_to_mempool(&self, drained: Vec<(Transaction, u64)>) {
+        let mut mempool = self.mempool();
+        for (transaction, added_at) in drained {
+            let _ = mempool.add_at(transaction, added_at);
+        }
+    }
+}
+
+fn handle_peer(shared: &Shared, mut stream: TcpStream) {
+    if set_timeouts(&stream, shared.timeout).is_err() {
+        return;
+    }
+    let height = chain_height(&shared.chain.lock().unwrap());
+    let (peer_height, listen_port) = match handshake(&mut stream, height, shared.listen_port()) {
+        Ok(version) => version,
+        Err(_) => return,
+    };
+    // known by where it accepts connections if it does, so it's the same peer whichever side dialed
+    let peer = match stream.peer_addr() {
+        Ok(addr) => listen_port.map_or(addr, |port| SocketAddr::new(addr.ip(), port)),
+        Err(_) => return,
+    };
+    // a peer we're already connected to the other way is served over that connection
+    let writer = match shared.register_peer(peer, &stream) {
+        Ok(Some(writer)) => writer,
+        _ => return,
+    };
+    info!("peer {} connected", peer);
+    // the answer comes back as a Blocks message like any other; if the send fails the
+    // connection is gone, which serving it finds out straight away
+    if peer_height > height {
+        let _ = send_message(&mut *writer.lock().unwrap(), &NetMessage::GetBlocks { from_height: height + 1 });
+    }
+    serve_peer(shared, stream, peer, writer);
+}
+
+// answer everything a peer sends until it hangs up or stops responding, then forget it;
+// replies go out through writer, the same as anything broadcast to the peer
+fn serve_peer(shared: &Shared, mut stream: TcpStream, peer: SocketAddr, writer: PeerStream) {
+    let chain = &shared.chain;
+    let reply = |message: &NetMessage| send_message(&mut *writer.lock().unwrap(), message);
+    let mut pinged = false;
+    loop {
+        let message = match receive_message(&mut stream) {
+            Ok(message) => message,
+            // a quiet peer gets one ping to show it's still there before it's dropped
+            Err(error) if error.kind() == io::ErrorKind::TimedOut && !pinged => {
+                pinged = true;
+                if reply(&NetMessage::Ping).is_err() {
+                    break;
+                }
+                continue;
+            }
+            Err(_) => break,
+        };
+        pinged = false;
+        match message {
+            NetMessage::Tx(transaction) => {
+                // bad signatures and duplicates just don't make it in
+                let _ = shared.gossip_transaction(transaction, Some(peer));
+            }
+            NetMessage::Block(block) => {
+                // blocks that don't decode are dropped
+                if let Ok(block) = block.to_block() {
+                    shared.append_block(block);
+                }
+            }
+            NetMessage::Blocks(blocks) => {
+                // every block of a page going in means the peer may well have more
+                let appended = shared.append_blocks(&blocks);
+                if !blocks.is_empty() && appended == blocks.len() {
+                    let from_height = chain_height(&chain.lock().unwrap()) + 1;
+                    if reply(&NetMessage::GetBlocks { from_height }).is_err() {
+                        break;
+                    }
+                }
+            }
+            NetMessage::GetBlocks { from_height } => {
+                let blocks = page_of_blocks(chain.lock().unwrap().blocks_from(from_height as usize));
+                if reply(&NetMessage::Blocks(blocks)).is_err() {
+                    break;
+                }
+            }
+            NetMessage::GetHeaders { from_height } => {
+                let headers = chain.lock().unwrap()
+                    .blocks_from(from_height as usize)
+                    .iter()
+                    .take(MAX_BLOCKS_PER_MESSAGE)
+                    .map(Block::header)
+                    .collect();
+                if reply(&NetMessage::Headers(headers)).is_err() {
+                    break;
+                }
+            }
+            NetMessage::GetBodies { hashes } => {
+                let wanted: HashSet<SHA256Hash> = hashes.into_iter().collect();
+                let blocks = page_of_blocks(chain.lock().unwrap().blocks().filter(|block| wanted.contains(block.hash())));
+                if reply(&NetMessage::Blocks(blocks)).is_err() {
+                    break;
+                }
+            }
+            NetMessage::Ping if reply(&NetMessage::Pong).is_err() => break,
+            _ => {}
+        }
+    }
+    shared.peers.lock().unwrap().remove_stream(&peer, &writer);
+    info!("peer {} disconnected", peer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{self, Blockchain, ChainParams, ConsensusMode, RejectReason, BLOCK_REWARD, COIN};
+
+    fn round_trip(message: &NetMessage) -> NetMessage {
+        let mut framed = Vec::new();
+        send_message(&mut framed, message).unwrap();
+        receive_message(&mut framed.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_framed_messages_over_tcp() {
+        let messages: Vec<Vec<u8>> = vec![
+            b"hello".to_vec(),
+            Vec::new(),
+            vec![7_u8; 100_000],
+            b"bye".to_vec(),
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sent = messages.clone();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for message in &sent {
+                write_message(&mut stream, message).unwrap();
+            }
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        for message in &messages {
+            assert_eq!(&read_message(&mut stream).unwrap(), message);
+        }
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_message_across_partial_reads() {
+        // a reader that hands out one byte per read call
+        struct OneByte(Vec<u8>, usize);
+        impl Read for OneByte {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.1 >= self.0.len() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.0[self.1];
+                self.1 += 1;
+                Ok(1)
+            }
+        }
+        let mut framed = Vec::new();
+        write_message(&mut framed, b"split up").unwrap();
+        let mut reader = OneByte(framed, 0);
+        assert_eq!(read_message(&mut reader).unwrap(), b"split up");
+        assert_eq!(read_message(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_read_message_refuses_oversized_frames() {
+        // only the length prefix is there, so reading a payload would fail with UnexpectedEof
+        let mut claims_too_much = &u32::MAX.to_be_bytes()[..];
+        assert_eq!(read_message(&mut claims_too_much).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut claims_just_over = &(MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes()[..];
+        assert_eq!(read_message(&mut claims_just_over).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut claims_the_max = &(MAX_MESSAGE_SIZE as u32).to_be_bytes()[..];
+        assert_eq!(read_message(&mut claims_the_max).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let oversized = vec![0_u8; MAX_MESSAGE_SIZE + 1];
+        assert_eq!(write_message(&mut Vec::new(), &oversized).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_message_times_out_on_missing_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (hang_up, hung_up) = std::sync::mpsc::channel::<()>();
+        let peer = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // promise 100 bytes and never send them
+            stream.write_all(&100_u32.to_be_bytes()).unwrap();
+            let _ = hung_up.recv();
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        set_timeouts(&stream, Duration::from_millis(100)).unwrap();
+        assert_eq!(read_message(&mut stream).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        hang_up.send(()).unwrap();
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_net_message_round_trip() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        let block = SerializedBlock::from_block(chain.last_block());
+        let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+
+        match round_trip(&NetMessage::Tx(transaction)) {
+            NetMessage::Tx(received) => {
+                assert_eq!(received.amount(), 10 * COIN);
+                assert_eq!(received.uid(), 1);
+                assert!(received.verify());
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        match round_trip(&NetMessage::Block(block.clone())) {
+            NetMessage::Block(received) => {
+                assert_eq!(received, block);
+                assert_eq!(*received.to_block().unwrap().hash(), chain.last_hash());
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        match round_trip(&NetMessage::GetBlocks { from_height: 7 }) {
+            NetMessage::GetBlocks { from_height } => assert_eq!(from_height, 7),
+            other => panic!("unexpected message {:?}", other),
+        }
+        match round_trip(&NetMessage::Blocks(vec![block.clone(), block.clone()])) {
+            NetMessage::Blocks(received) => assert_eq!(received, vec![block.clone(), block]),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(matches!(round_trip(&NetMessage::Ping), NetMessage::Ping));
+        assert!(matches!(round_trip(&NetMessage::Pong), NetMessage::Pong));
+        match round_trip(&NetMessage::Version { protocol_version: 3, chain_height: 12, listen_port: Some(8333) }) {
+            NetMessage::Version { protocol_version, chain_height, listen_port } => {
+                assert_eq!(protocol_version, 3);
+                assert_eq!(chain_height, 12);
+                assert_eq!(listen_port, Some(8333));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_receive_message_rejects_garbage() {
+        let mut framed = Vec::new();
+        write_message(&mut framed, b"not a message").unwrap();
+        let error = receive_message(&mut framed.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    // wait for a node's chain to reach len blocks, handling happens on another thread
+    fn wait_for_len(node: &Node, len: usize) -> bool {
+        for _ in 0..500 {
+            if node.chain().len() >= len {
+                return true;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        false
+    }
+
+    // wait for a node to have count peers, since the listening side adds them on another thread
+    fn wait_for_peers(node: &Node, count: usize) -> bool {
+        for _ in 0..500 {
+            if node.peers().len() >= count {
+                return true;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        false
+    }
+
+    // whether handle finishes within a couple of seconds
+    fn joins_in_time<T: Send + 'static>(handle: thread::JoinHandle<T>) -> bool {
+        let (done, finished) = std::sync::mpsc::channel();
+        thread::spawn(move || done.send(handle.join().is_ok()));
+        finished.recv_timeout(Duration::from_secs(2)).unwrap_or(false)
+    }
+
+    #[test]
+    fn test_server_returns_after_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let signal = Arc::clone(&shutdown);
+        let server = thread::spawn(move || server(listener, signal).unwrap());
+
+        // a connection made before shutdown is still served
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"Hello!").unwrap();
+        let mut reply = [0_u8; 6];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"Hello!");
+        shutdown.store(true, Ordering::SeqCst);
+        drop(stream);
+        assert!(joins_in_time(server));
+    }
+
+    #[test]
+    fn test_node_stops_listening_after_shutdown() {
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = node.listen(listener);
+        let other = Node::new(Blockchain::with_difficulty(8));
+        other.connect(addr).unwrap();
+        node.shutdown();
+        assert!(joins_in_time(server));
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_mined_block_reaches_peer() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let receiver = Node::new(Blockchain::with_difficulty(8));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        receiver.listen(listener);
+
+        let miner = Node::new(Blockchain::with_difficulty(8));
+        miner.connect(addr).unwrap();
+
+        // a block that doesn't extend the receiver's chain isn't appended
+        let (private_key2, _public_key2) = blockchain::generate_key_pair().unwrap();
+        let mut other = Blockchain::with_difficulty(8);
+        other.add_block(Vec::new(), &private_key2).unwrap();
+        other.add_block(Vec::new(), &private_key2).unwrap();
+        miner.broadcast_block(other.last_block());
+
+        miner.mine_block(Vec::new(), &private_key).unwrap();
+        assert!(wait_for_len(&receiver, 2));
+        assert_eq!(receiver.chain().len(), 2);
+        assert_eq!(receiver.chain().last_hash(), miner.chain().last_hash());
+        assert_eq!(receiver.chain().balance_of(&public_key), 50 * COIN);
+    }
+
+    #[test]
+    fn test_block_mined_by_listener_reaches_dialer() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let listening = Node::new(Blockchain::with_difficulty(8));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listening.listen(listener);
+
+        let dialing = Node::new(Blockchain::with_difficulty(8));
+        let dialing_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dialing_addr = dialing_listener.local_addr().unwrap();
+        dialing.listen(dialing_listener);
+        dialing.connect(addr).unwrap();
+        // both sides know the connection by where the other one listens
+        assert!(wait_for_peers(&listening, 1));
+        assert_eq!(listening.peers().addrs(), vec![dialing_addr]);
+        assert_eq!(dialing.peers().addrs(), vec![addr]);
+        listening.connect(dialing_addr).unwrap();
+        assert_eq!(listening.peers().len(), 1);
+
+        listening.mine_block(Vec::new(), &private_key).unwrap();
+        assert!(wait_for_len(&dialing, 2));
+        assert_eq!(dialing.chain().last_hash(), listening.chain().last_hash());
+        assert_eq!(dialing.chain().balance_of(&public_key), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn test_node_rejects_unsupported_version() {
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        node.listen(listener);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        match receive_message(&mut stream).unwrap() {
+            NetMessage::Version { protocol_version, chain_height, listen_port } => {
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+                assert_eq!(chain_height, 0);
+                assert_eq!(listen_port, Some(addr.port()));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        send_message(&mut stream, &NetMessage::Version { protocol_version: PROTOCOL_VERSION + 1, chain_height: 0, listen_port: None }).unwrap();
+        // the node hangs up instead of answering
+        let _ = send_message(&mut stream, &NetMessage::Ping);
+        assert!(receive_message(&mut stream).is_err());
+    }
+
+    #[test]
+    fn test_connect_rejects_unsupported_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = send_message(&mut stream, &NetMessage::Version { protocol_version: PROTOCOL_VERSION + 1, chain_height: 0, listen_port: None });
+            let _ = receive_message(&mut stream);
+        });
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let error = node.connect(addr).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(node.peers().is_empty());
+        peer.join().unwrap();
+    }
+
+    // a chain where miner has a block reward to spend
+    fn funded_chain(miner: &RsaPrivateKey) -> Blockchain {
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), miner).unwrap();
+        chain
+    }
+
+    fn five_block_chain(miner: &RsaPrivateKey) -> Blockchain {
+        let mut chain = Blockchain::with_difficulty(8);
+        for _ in 0..5 {
+            chain.add_block(Vec::new(), miner).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn test_connecting_node_syncs_from_taller_peer() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let tall = Node::new(five_block_chain(&private_key));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tall.listen(listener);
+
+        let fresh = Node::new(Blockchain::with_difficulty(8));
+        fresh.connect(addr).unwrap();
+        assert_eq!(fresh.chain().len(), 6);
+        assert_eq!(fresh.chain().last_hash(), tall.chain().last_hash());
+        assert_eq!(fresh.chain().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_headers_first_sync() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let mut chain = five_block_chain(&private_key);
+        let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2, COIN, 1);
+        chain.add_block(vec![payment], &private_key).unwrap();
+        let tall = Node::new(chain);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tall.listen(listener);
+
+        let fresh = Node::new(Blockchain::with_difficulty(8));
+        fresh.set_sync_mode(SyncMode::HeadersFirst);
+        fresh.connect(addr).unwrap();
+        assert_eq!(fresh.chain().len(), 7);
+        assert_eq!(fresh.chain().last_hash(), tall.chain().last_hash());
+        assert_eq!(fresh.chain().last_block().transactions().len(), 2);
+        assert_eq!(fresh.chain().validate(), Ok(()));
+    }
+
+    // more blocks than fit in one reply, mined without retargeting getting in the way
+    fn paged_params() -> ChainParams {
+        ChainParams { initial_difficulty: 8, target_block_seconds: 0, ..ChainParams::default() }
+    }
+
+    fn paged_chain(miner: &RsaPrivateKey) -> Blockchain {
+        let mut chain = Blockchain::new(paged_params());
+        for _ in 0..MAX_BLOCKS_PER_MESSAGE + 20 {
+            chain.add_block(Vec::new(), miner).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn test_sync_pages_through_long_chain() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let tall = Node::new(paged_chain(&private_key));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tall.listen(listener);
+
+        // a single reply stops at a page
+        let mut stream = TcpStream::connect(addr).unwrap();
+        handshake(&mut stream, 0, None).unwrap();
+        send_message(&mut stream, &NetMessage::GetBlocks { from_height: 1 }).unwrap();
+        let page = loop {
+            if let NetMessage::Blocks(blocks) = receive_message(&mut stream).unwrap() {
+                break blocks;
+            }
+        };
+        assert_eq!(page.len(), MAX_BLOCKS_PER_MESSAGE);
+
+        for mode in [SyncMode::Blocks, SyncMode::HeadersFirst] {
+            let fresh = Node::new(Blockchain::new(paged_params()));
+            fresh.set_sync_mode(mode);
+            fresh.connect(addr).unwrap();
+            assert_eq!(fresh.chain().len(), tall.chain().len());
+            assert_eq!(fresh.chain().last_hash(), tall.chain().last_hash());
+            assert_eq!(fresh.chain().validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_listening_node_pages_through_long_chain() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let fresh = Node::new(Blockchain::new(paged_params()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        fresh.listen(listener);
+
+        let tall = Node::new(paged_chain(&private_key));
+        tall.connect(addr).unwrap();
+        let len = tall.chain().len();
+        assert!(wait_for_len(&fresh, len));
+        assert_eq!(fresh.chain().last_hash(), tall.chain().last_hash());
+    }
+
+    #[test]
+    fn test_valid_headers_stop_at_bad_link_or_work() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let chain = five_block_chain(&private_key);
+        let headers: Vec<BlockHeader> = chain.blocks_from(1).iter().map(Block::header).collect();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        assert_eq!(node.valid_headers(1, headers.clone()), headers);
+        // nothing to link the headers to
+        assert!(node.valid_headers(2, headers.clone()).is_empty());
+
+        // everything from the first bad header on is cut off
+        let tamper = |field: &str, value: serde_json::Value| {
+            let mut tampered = headers.clone();
+            let mut header = serde_json::to_value(&tampered[2]).unwrap();
+            header[field] = value;
+            tampered[2] = serde_json::from_value(header).unwrap();
+            node.valid_headers(1, tampered)
+        };
+        // claiming far more work than the hash shows
+        assert_eq!(tamper("difficulty", serde_json::json!(200)), headers[..2]);
+        let elsewhere = serde_json::to_value(headers[0].previous_hash()).unwrap();
+        assert_eq!(tamper("previous_hash", elsewhere), headers[..2]);
+    }
+
+    #[test]
+    fn test_auto_miner_mines_submitted_transaction() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (private_key2, public_key2) = blockchain::generate_key_pair().unwrap();
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        let node = Node::new(chain);
+        let miner = node.start_mining(private_key2.clone());
+
+        // nothing gets mined while the mempool is empty
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(node.chain().len(), 2);
+        let payment = Transaction::new(public_key.clone(), private_key, public_key2.clone(), 10 * COIN, 1);
+        node.broadcast_transaction(payment.clone()).unwrap();
+        assert!(wait_for_len(&node, 3));
+        assert_eq!(node.chain().last_block().transactions()[1..], [payment]);
+        assert!(node.mempool().is_empty());
+        assert_eq!(node.chain().balance_of(&public_key2), 10 * COIN + BLOCK_REWARD);
+
+        node.shutdown();
+        assert!(joins_in_time(miner));
+    }
+
+    #[test]
+    fn test_sync_respects_checkpoints() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let (private_key2, _public_key2) = blockchain::generate_key_pair().unwrap();
+        let honest = Node::new(five_block_chain(&private_key));
+        let forked = Node::new(five_block_chain(&private_key2));
+        let addrs: Vec<SocketAddr> = [&honest, &forked].iter().map(|node| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            node.listen(listener);
+            addr
+        }).collect();
+
+        let fresh = Node::new(Blockchain::with_difficulty(8));
+        let checkpoint = *honest.chain().block_at(2).unwrap().hash();
+        fresh.add_checkpoint(2, checkpoint).unwrap();
+        fresh.connect(addrs[1]).unwrap();
+        assert_eq!(fresh.chain().len(), 1);
+        fresh.connect(addrs[0]).unwrap();
+        assert_eq!(fresh.chain().last_hash(), honest.chain().last_hash());
+
+        // a chain that already disagrees can't take the checkpoint
+        assert_eq!(forked.add_checkpoint(2, checkpoint), Err(ValidationError::CheckpointMismatch { index: 2 }));
+    }
+
+    #[test]
+    fn test_listening_node_syncs_from_taller_peer() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let fresh = Node::new(Blockchain::with_difficulty(8));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        fresh.listen(listener);
+
+        // the taller node dials, so it's the one answering the listener's request for blocks
+        let tall = Node::new(five_block_chain(&private_key));
+        tall.connect(addr).unwrap();
+        assert_eq!(tall.chain().len(), 6);
+        assert!(wait_for_len(&fresh, 6));
+        assert_eq!(fresh.chain().last_hash(), tall.chain().last_hash());
+        assert_eq!(fresh.chain().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_broadcast_transaction_reaches_peer_mempool() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let receiver = Node::new(funded_chain(&private_key));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        receiver.listen(listener);
+
+        let sender = Node::new(funded_chain(&private_key));
+        sender.connect(addr).unwrap();
+        let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+        sender.broadcast_transaction(transaction).unwrap();
+        assert_eq!(sender.mempool().len(), 1);
+        for _ in 0..500 {
+            if !receiver.mempool().is_empty() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(receiver.mempool().len(), 1);
+    }
+
+    #[test]
+    fn test_broadcast_block_skips_closed_peer() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        let block = chain.last_block().clone();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let mut readers = Vec::new();
+        let mut closed = None;
+        for i in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let stream = TcpStream::connect(addr).unwrap();
+            let (accepted, _) = listener.accept().unwrap();
+            set_timeouts(&accepted, DEFAULT_TIMEOUT).unwrap();
+            // the middle one can't be written to any more
+            if i == 1 {
+                stream.shutdown(Shutdown::Write).unwrap();
+                closed = Some(addr);
+            }
+            assert!(node.peers().add(addr, stream));
+            readers.push(accepted);
+        }
+        node.broadcast_block(&block);
+        for (i, reader) in readers.iter_mut().enumerate().filter(|(i, _)| *i != 1) {
+            match receive_message(reader) {
+                Ok(NetMessage::Block(received)) => assert_eq!(received.to_block().unwrap().hash(), block.hash()),
+                other => panic!("peer {} got {:?} instead of the block", i, other),
+            }
+        }
+        assert_eq!(node.peers().len(), 2);
+        assert!(!node.peers().contains(&closed.unwrap()));
+    }
+
+    #[test]
+    fn test_orphan_connects_when_parent_arrives() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        for _ in 0..3 {
+            chain.add_block(Vec::new(), &private_key).unwrap();
+        }
+        let parent = chain.block_at(1).unwrap().clone();
+        // the grandchildren turn up first, out of order between themselves too
+        for height in [3, 2, 4] {
+            node.shared.append_block(chain.block_at(height).unwrap().clone());
+        }
+        assert_eq!(node.chain().len(), 1);
+        assert_eq!(node.shared.orphans.lock().unwrap().len(), 3);
+
+        node.shared.append_block(parent);
+        assert_eq!(node.chain().last_hash(), chain.last_hash());
+        assert!(node.shared.orphans.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_orphan_past_checkpoint_is_held() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let chain = five_block_chain(&private_key);
+        let node = Node::new(Blockchain::with_difficulty(8));
+        node.add_checkpoint(1, *chain.block_at(1).unwrap().hash()).unwrap();
+        // it'd contradict the checkpoint at the tip's height, but it belongs further up
+        node.shared.append_block(chain.block_at(2).unwrap().clone());
+        assert_eq!(node.shared.orphans.lock().unwrap().len(), 1);
+
+        node.shared.append_block(chain.block_at(1).unwrap().clone());
+        assert_eq!(node.chain().len(), 3);
+        assert_eq!(node.chain().last_hash(), *chain.block_at(2).unwrap().hash());
+    }
+
+    #[test]
+    fn test_broadcast_transaction_checks_chain() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let transaction = Transaction::new(public_key, private_key.clone(), public_key2, 10 * COIN, 1);
+        assert_eq!(node.broadcast_transaction(transaction.clone()), Err(MempoolError::Rejected(RejectReason::InsufficientFunds)));
+        assert!(node.mempool().is_empty());
+
+        // being turned away once doesn't count as seen, so it's admitted once it can be covered
+        node.mine_block(Vec::new(), &private_key).unwrap();
+        assert_eq!(node.broadcast_transaction(transaction.clone()), Ok(()));
+        assert_eq!(node.mempool().len(), 1);
+        assert_eq!(node.broadcast_transaction(transaction), Ok(()));
+        assert_eq!(node.mempool().len(), 1);
+    }
+
+    #[test]
+    fn test_relayed_transaction_skips_its_sender() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let node = Node::new(funded_chain(&private_key));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        node.listen(listener);
+        // each peer claims to listen somewhere, which is the address the node knows it by
+        let mut peers: Vec<TcpStream> = [9001, 9002].iter().map(|&port| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            set_timeouts(&stream, DEFAULT_TIMEOUT).unwrap();
+            handshake(&mut stream, 1, Some(port)).unwrap();
+            stream
+        }).collect();
+        assert!(wait_for_peers(&node, 2));
+
+        let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+        send_message(&mut peers[0], &NetMessage::Tx(transaction.clone())).unwrap();
+        send_message(&mut peers[0], &NetMessage::Ping).unwrap();
+        // messages are handled in order, so an echo would have come before the pong
+        assert!(matches!(receive_message(&mut peers[0]).unwrap(), NetMessage::Pong));
+        match receive_message(&mut peers[1]).unwrap() {
+            NetMessage::Tx(received) => assert_eq!(received.hash(), transaction.hash()),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_seen_set_forgets_oldest() {
+        let mut seen = SeenSet::default();
+        let hashes: Vec<SHA256Hash> = (0..=MAX_SEEN as u64).map(|i| blockchain::sha256(&i.to_be_bytes())).collect();
+        for hash in &hashes {
+            seen.insert(*hash);
+        }
+        seen.insert(hashes[1]);
+        assert_eq!(seen.len(), MAX_SEEN);
+        assert!(!seen.contains(&hashes[0]));
+        assert!(seen.contains(&hashes[1]));
+        assert!(seen.contains(&hashes[MAX_SEEN]));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let empty = NodeMetrics { chain_height: 0, mempool_size: 0, peer_count: 0, total_transactions: 0, difficulty: 8 };
+        assert_eq!(node.metrics(), empty);
+
+        node.mine_block(Vec::new(), &private_key).unwrap();
+        let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key2.clone(), COIN, 1);
+        node.mine_block(vec![payment], &private_key).unwrap();
+        node.broadcast_transaction(Transaction::new(public_key, private_key, public_key2, COIN, 2)).unwrap();
+        let metrics = node.metrics();
+        assert_eq!(metrics, NodeMetrics { chain_height: 2, mempool_size: 1, total_transactions: 1, ..empty });
+    }
+
+    #[test]
+    fn test_detect_forks() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let (private_key2, _public_key2) = blockchain::generate_key_pair().unwrap();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        node.mine_block(Vec::new(), &private_key).unwrap();
+        assert!(node.detect_forks().is_empty());
+
+        // a rival block on the same genesis can't be appended, but it's still a fork
+        let mut rival = Blockchain::with_difficulty(8);
+        rival.add_block(Vec::new(), &private_key2).unwrap();
+        node.shared.append_block(rival.last_block().clone());
+        assert_eq!(node.chain().len(), 2);
+        let genesis = *node.chain().block_at(0).unwrap().hash();
+        let ours = node.chain().last_hash();
+        assert_eq!(node.detect_forks(), vec![(genesis, vec![ours, rival.last_hash()])]);
+
+        // hearing about the same block again doesn't make it a bigger fork
+        node.shared.append_block(rival.last_block().clone());
+        assert_eq!(node.detect_forks()[0].1.len(), 2);
+        node.mine_block(Vec::new(), &private_key).unwrap();
+        assert_eq!(node.detect_forks().len(), 1);
+    }
+
+    #[test]
+    fn test_reorg_returns_transactions_to_mempool() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (private_key2, _public_key2) = blockchain::generate_key_pair().unwrap();
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        let mut branch: Blockchain = serde_json::from_str(&serde_json::to_string(&chain).unwrap()).unwrap();
+        branch.add_block(Vec::new(), &private_key2).unwrap();
+        branch.add_block(Vec::new(), &private_key2).unwrap();
+
+        let node = Node::new(chain);
+        let payment = Transaction::new(public_key.clone(), private_key.clone(), public_key.clone(), COIN, 1);
+        node.mine_block(vec![payment.clone()], &private_key).unwrap();
+        assert!(node.mempool().is_empty());
+        node.reorg_to(branch.blocks_from(3).to_vec()).unwrap();
+        assert_eq!(node.chain().last_hash(), branch.last_hash());
+        assert_eq!(node.mempool().drain_for_block(10), vec![payment]);
+        assert_eq!(node.detect_forks().len(), 1);
+    }
+
+    #[test]
+    fn test_reorg_respects_checkpoints() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let (private_key2, _public_key2) = blockchain::generate_key_pair().unwrap();
+        let mut chain = Blockchain::with_difficulty(8);
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        let mut branch: Blockchain = serde_json::from_str(&serde_json::to_string(&chain).unwrap()).unwrap();
+        chain.add_block(Vec::new(), &private_key).unwrap();
+        for _ in 0..3 {
+            branch.add_block(Vec::new(), &private_key2).unwrap();
+        }
+
+        let node = Node::new(chain);
+        let tip = node.chain().last_hash();
+        node.add_checkpoint(2, tip).unwrap();
+        let refused = Err(ReorgError::Invalid(BlockError::Invalid(ValidationError::CheckpointMismatch { index: 2 })));
+        assert_eq!(node.reorg_to(branch.blocks_from(2).to_vec()), refused);
+        assert_eq!(node.chain().last_hash(), tip);
+        assert!(node.detect_forks().is_empty());
+    }
+
+    #[test]
+    fn test_miner_idles_when_not_a_validator() {
+        let (validator_key, validator) = blockchain::generate_key_pair().unwrap();
+        let (outsider_key, _outsider) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let params = ChainParams { consensus: ConsensusMode::ProofOfAuthority { validators: vec![validator.clone()] }, ..ChainParams::default() };
+        let node = Node::new(Blockchain::new(params));
+        let payment = Transaction::new(validator, validator_key, public_key2, COIN, 1);
+        node.mempool().add(payment.clone()).unwrap();
+        // nothing it could mine, so the transaction waits and the miner sleeps
+        assert!(!node.mine_from_mempool(&outsider_key));
+        assert_eq!(node.chain().len(), 1);
+        assert_eq!(node.mempool().drain_for_block(10), vec![payment]);
+    }
+
+    #[test]
+    fn test_peer_set_dedups_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peers = PeerSet::new();
+        assert!(peers.add(addr, TcpStream::connect(addr).unwrap()));
+        assert!(!peers.add(addr, TcpStream::connect(addr).unwrap()));
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers.addrs(), vec![addr]);
+        // only the connection that's there now can be removed by its stream
+        let replaced = Arc::new(Mutex::new(TcpStream::connect(addr).unwrap()));
+        assert!(!peers.remove_stream(&addr, &replaced));
+        let current = peers.get(&addr).unwrap();
+        assert!(peers.remove_stream(&addr, &current));
+        assert!(peers.add(addr, TcpStream::connect(addr).unwrap()));
+        assert!(peers.remove(&addr).is_some());
+        assert!(peers.remove(&addr).is_none());
+        assert!(peers.is_empty());
+    }
+
+    #[test]
+    fn test_connect_twice_keeps_one_peer() {
+        let node = Node::new(Blockchain::with_difficulty(8));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        node.listen(listener);
+
+        let other = Node::new(Blockchain::with_difficulty(8));
+        other.connect(addr).unwrap();
+        other.connect(addr).unwrap();
+        assert_eq!(other.peers().addrs(), vec![addr]);
+    }
+
+    #[test]
+    fn test_transaction_gossip_reaches_every_node_once() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let nodes: Vec<Node> = (0..3).map(|_| Node::new(funded_chain(&private_key))).collect();
+        let mut addrs = Vec::new();
+        for node in &nodes {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            node.listen(listener);
+        }
+        // a ring, so without the seen set the transaction would circle forever
+        for (i, node) in nodes.iter().enumerate() {
+            node.connect(addrs[(i + 1) % 3]).unwrap();
+        }
+
+        let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+        let hash = transaction.hash();
+        nodes[0].broadcast_transaction(transaction).unwrap();
+        for _ in 0..500 {
+            if nodes.iter().all(|node| node.shared.seen.lock().unwrap().contains(&hash)) {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // let the last hop back to node 0 land before checking nothing doubled up
+        thread::sleep(std::time::Duration::from_millis(100));
+        for node in &nodes {
+            assert_eq!(node.mempool().len(), 1);
+            assert_eq!(node.shared.seen.lock().unwrap().len(), 1);
+        }
+    }
+}
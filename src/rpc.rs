@@ -0,0 +1,139 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use serde::{Serialize, Deserialize};
+use crate::blockchain::{Address, Block, SHA256Hash, Transaction};
+use crate::network::{self, Node};
+
+// a query from a wallet or explorer, e.g. {"method": "get_block", "height": 1}
+// requests are parsed, answered and dropped, so the size of SubmitTx doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum RpcRequest {
+    GetHeight,
+    GetBlock { height: u64 },
+    GetBalance { address: Address },
+    SubmitTx { transaction: Transaction },
+}
+
+// the answer to an RpcRequest, e.g. {"height": 5} or {"error": "..."};
+// as short lived as requests, so Block being big doesn't matter either
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcResponse {
+    Height(u64),
+    Block(Block),
+    Balance(u64),
+    // hash of the accepted transaction
+    Submitted(SHA256Hash),
+    Error(String),
+}
+
+pub fn handle(node: &Node, request: RpcRequest) -> RpcResponse {
+    match request {
+        RpcRequest::GetHeight => RpcResponse::Height(node.chain().len() as u64 - 1),
+        RpcRequest::GetBlock { height } => match node.chain().block_at(height) {
+            Some(block) => RpcResponse::Block(block.clone()),
+            None => RpcResponse::Error(format!("no block at height {}", height)),
+        },
+        RpcRequest::GetBalance { address } => RpcResponse::Balance(node.chain().balance_of_address(&address)),
+        RpcRequest::SubmitTx { transaction } => {
+            let hash = transaction.hash();
+            match node.broadcast_transaction(transaction) {
+                Ok(()) => RpcResponse::Submitted(hash),
+                Err(error) => RpcResponse::Error(error.to_string()),
+            }
+        }
+    }
+}
+
+// answers one framed JSON request after another until the client hangs up
+fn handle_client(node: &Node, mut stream: TcpStream) {
+    while let Ok(payload) = network::read_message(&mut stream) {
+        let response = match serde_json::from_slice(&payload) {
+            Ok(request) => handle(node, request),
+            Err(error) => RpcResponse::Error(format!("bad request: {}", error)),
+        };
+        let response = serde_json::to_vec(&response).expect("responses always serialize");
+        if network::write_message(&mut stream, &response).is_err() {
+            break;
+        }
+    }
+}
+
+// serve queries on listener in the background until the node shuts down
+pub fn serve(node: Node, listener: TcpListener) -> io::Result<thread::JoinHandle<()>> {
+    // set up front so a listener that can't be polled fails here rather than in the thread
+    listener.set_nonblocking(true)?;
+    Ok(thread::spawn(move || {
+        let incoming = match node.incoming(&listener) {
+            Ok(incoming) => incoming,
+            Err(_) => return,
+        };
+        for stream in incoming {
+            let node = node.clone();
+            thread::spawn(move || handle_client(&node, stream));
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{self, Blockchain, COIN};
+
+    fn request(stream: &mut TcpStream, request: &str) -> serde_json::Value {
+        network::write_message(stream, request.as_bytes()).unwrap();
+        serde_json::from_slice(&network::read_message(stream).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_get_height_from_running_node() {
+        let (private_key, _public_key) = blockchain::generate_key_pair().unwrap();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        node.mine_block(Vec::new(), &private_key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(node.clone(), listener).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(request(&mut stream, r#"{"method": "get_height"}"#), serde_json::json!({ "height": 1 }));
+        let reply = request(&mut stream, "get_height please");
+        assert!(reply["error"].as_str().unwrap().starts_with("bad request"));
+        node.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_requests() {
+        let (private_key, public_key) = blockchain::generate_key_pair().unwrap();
+        let (_, public_key2) = blockchain::generate_key_pair().unwrap();
+        let node = Node::new(Blockchain::with_difficulty(8));
+        node.mine_block(Vec::new(), &private_key).unwrap();
+
+        match handle(&node, RpcRequest::GetBlock { height: 1 }) {
+            RpcResponse::Block(block) => assert_eq!(*block.hash(), node.chain().last_hash()),
+            _ => panic!("expected a block"),
+        }
+        assert!(matches!(handle(&node, RpcRequest::GetBlock { height: 2 }), RpcResponse::Error(_)));
+        let address = Address::from_public_key(&public_key);
+        assert!(matches!(handle(&node, RpcRequest::GetBalance { address }), RpcResponse::Balance(balance) if balance == 50 * COIN));
+
+        let transaction = Transaction::new(public_key, private_key, public_key2, 10 * COIN, 1);
+        let hash = transaction.hash();
+        let submit = RpcRequest::SubmitTx { transaction: transaction.clone() };
+        assert!(matches!(handle(&node, submit), RpcResponse::Submitted(submitted) if submitted == hash));
+        assert_eq!(node.mempool().len(), 1);
+    }
+
+    #[test]
+    fn test_request_json() {
+        let request: RpcRequest = serde_json::from_str(r#"{"method": "get_block", "height": 3}"#).unwrap();
+        assert_eq!(request, RpcRequest::GetBlock { height: 3 });
+        let address = Address::from_public_key(&blockchain::generate_key_pair().unwrap().1);
+        let json = format!(r#"{{"method": "get_balance", "address": "{}"}}"#, address);
+        assert_eq!(serde_json::from_str::<RpcRequest>(&json).unwrap(), RpcRequest::GetBalance { address });
+    }
+}
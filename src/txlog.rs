@@ -0,0 +1,134 @@
+use crate::blockchain::{self, Address, Blockchain, Transaction};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum LogError {
+  Io(io::Error),
+  Parse { line: usize, error: serde_json::Error },
+}
+
+impl fmt::Display for LogError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LogError::Io(error) => write!(f, "failed to read transaction log: {}", error),
+      LogError::Parse { line, error } => write!(f, "line {} of the transaction log is invalid: {}", line, error),
+    }
+  }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<io::Error> for LogError {
+  fn from(error: io::Error) -> Self {
+    LogError::Io(error)
+  }
+}
+
+// an append only file of accepted transactions, one JSON object per line, so a crash
+// mid write costs at most the last line
+pub struct TransactionLog {
+  path: PathBuf,
+}
+
+impl TransactionLog {
+  // nothing is touched on disk until the first append
+  pub fn open(path: &Path) -> TransactionLog {
+    TransactionLog { path: path.to_path_buf() }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn append(&self, transaction: &Transaction) -> io::Result<()> {
+    let mut line = serde_json::to_vec(transaction)?;
+    line.push(b'\n');
+    OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(&line)
+  }
+
+  // every transaction in the chain that's still there, coinbases included, in chain order
+  pub fn export_chain(&self, chain: &Blockchain) -> io::Result<()> {
+    for transaction in chain.transactions() {
+      self.append(transaction)?;
+    }
+    Ok(())
+  }
+
+  // a log that was never written to is empty
+  pub fn transactions(&self) -> Result<Vec<Transaction>, LogError> {
+    let file = match File::open(&self.path) {
+      Ok(file) => file,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error.into()),
+    };
+    let mut transactions = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+      let line = line?;
+      if line.is_empty() {
+        continue;
+      }
+      let transaction = serde_json::from_str(&line).map_err(|error| LogError::Parse { line: index + 1, error })?;
+      transactions.push(transaction);
+    }
+    Ok(transactions)
+  }
+}
+
+// balances as the chain would report them for the same transactions; nothing vets the log
+// the way blocks are vetted before joining the chain, so ones that don't verify are skipped
+pub fn rebuild_balances(log: &TransactionLog) -> Result<HashMap<Address, u64>, LogError> {
+  let mut balances = HashMap::new();
+  for transaction in log.transactions()?.iter().filter(|transaction| transaction.verify()) {
+    blockchain::apply_to_balances(&mut balances, transaction);
+  }
+  Ok(blockchain::positive_balances(balances))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blockchain::{generate_key_pair, COIN};
+  use std::fs;
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("shitcoin-{}-{}.jsonl", std::process::id(), name))
+  }
+
+  #[test]
+  fn test_rebuild_balances_matches_chain() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let (private_key2, public_key2) = generate_key_pair().unwrap();
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &private_key).unwrap();
+    let payment = Transaction::with_fee(public_key.clone(), private_key.clone(), public_key2.clone(), 10 * COIN, COIN, 1);
+    let split = Transaction::with_outputs(public_key.clone(), private_key, vec![(public_key2.clone(), COIN), (public_key.clone(), 2 * COIN)], 2);
+    chain.add_block(vec![payment, split], &private_key2).unwrap();
+
+    let path = temp_path("rebuild");
+    let _ = fs::remove_file(&path);
+    let log = TransactionLog::open(&path);
+    assert!(rebuild_balances(&log).unwrap().is_empty());
+    log.export_chain(&chain).unwrap();
+    let balances = rebuild_balances(&log).unwrap();
+    assert_eq!(log.transactions().unwrap().len(), 4);
+    fs::remove_file(&path).unwrap();
+    for key in [&public_key, &public_key2] {
+      let address = Address::from_public_key(key);
+      assert_eq!(balances[&address], chain.balance_of(key));
+    }
+    assert_eq!(balances.len(), 2);
+  }
+
+  #[test]
+  fn test_corrupt_line_is_reported() {
+    let path = temp_path("corrupt");
+    fs::write(&path, "{\"not\": \"a transaction\"}\n").unwrap();
+    let result = TransactionLog::open(&path).transactions();
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(LogError::Parse { line: 1, .. })));
+  }
+}
@@ -0,0 +1,196 @@
+use crate::blockchain::{self, Address, Blockchain, KeyGenError, Transaction};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs8::{FromPrivateKey, FromPublicKey, ToPrivateKey, ToPublicKey};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum KeyError {
+  Io(io::Error),
+  Pkcs8(rsa::pkcs8::Error),
+}
+
+impl fmt::Display for KeyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      KeyError::Io(error) => write!(f, "failed to access key file: {}", error),
+      KeyError::Pkcs8(error) => write!(f, "failed to encode or decode key: {}", error),
+    }
+  }
+}
+
+impl std::error::Error for KeyError {}
+
+impl From<io::Error> for KeyError {
+  fn from(error: io::Error) -> Self {
+    KeyError::Io(error)
+  }
+}
+
+impl From<rsa::pkcs8::Error> for KeyError {
+  fn from(error: rsa::pkcs8::Error) -> Self {
+    KeyError::Pkcs8(error)
+  }
+}
+
+// keys are stored as PKCS#8 PEM
+pub fn save_private_key(key: &RsaPrivateKey, path: &Path) -> Result<(), KeyError> {
+  let pem = key.to_pkcs8_pem()?;
+  fs::write(path, pem.as_bytes())?;
+  Ok(())
+}
+
+pub fn load_private_key(path: &Path) -> Result<RsaPrivateKey, KeyError> {
+  let pem = fs::read_to_string(path)?;
+  Ok(RsaPrivateKey::from_pkcs8_pem(&pem)?)
+}
+
+pub fn save_public_key(key: &RsaPublicKey, path: &Path) -> Result<(), KeyError> {
+  let pem = key.to_public_key_pem()?;
+  fs::write(path, pem)?;
+  Ok(())
+}
+
+pub fn load_public_key(path: &Path) -> Result<RsaPublicKey, KeyError> {
+  let pem = fs::read_to_string(path)?;
+  Ok(RsaPublicKey::from_public_key_pem(&pem)?)
+}
+
+// a key pair and the everyday things done with it
+pub struct Wallet {
+  private_key: RsaPrivateKey,
+  public_key: RsaPublicKey,
+  // uids come from derive_uid, so only this wallet's own ones could collide; counting up
+  // from a random point keeps a reloaded wallet off the ones it already used, and starting
+  // in the lower half leaves room to count without wrapping
+  next_counter: u32,
+}
+
+impl Wallet {
+  pub fn new() -> Result<Wallet, KeyGenError> {
+    let (private_key, public_key) = blockchain::generate_key_pair()?;
+    Ok(Wallet { private_key, public_key, next_counter: rand::random::<u32>() >> 1 })
+  }
+
+  pub fn from_private_key(private_key: RsaPrivateKey) -> Wallet {
+    let public_key = RsaPublicKey::from(&private_key);
+    Wallet { private_key, public_key, next_counter: rand::random::<u32>() >> 1 }
+  }
+
+  pub fn load(path: &Path) -> Result<Wallet, KeyError> {
+    Ok(Wallet::from_private_key(load_private_key(path)?))
+  }
+
+  pub fn save(&self, path: &Path) -> Result<(), KeyError> {
+    save_private_key(&self.private_key, path)
+  }
+
+  pub fn public_key(&self) -> &RsaPublicKey {
+    &self.public_key
+  }
+
+  pub fn address(&self) -> Address {
+    Address::from_public_key(&self.public_key)
+  }
+
+  pub fn balance(&self, chain: &Blockchain) -> u64 {
+    chain.balance_of(&self.public_key)
+  }
+
+  // signed and ready for the mempool, under the next uid in the wallet's sequence
+  pub fn create_transaction(&mut self, to: &RsaPublicKey, amount: u64, fee: u64) -> Transaction {
+    let uid = blockchain::derive_uid(&self.address(), self.next_counter);
+    self.next_counter += 1;
+    self.create_transaction_with_uid(to, amount, uid, fee)
+  }
+
+  pub fn create_transaction_with_uid(&self, to: &RsaPublicKey, amount: u64, uid: u64, fee: u64) -> Transaction {
+    Transaction::with_fee(self.public_key.clone(), self.private_key.clone(), to.clone(), amount, fee, uid)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blockchain::{generate_key_pair, sign_message, verify_message, Transaction, COIN};
+  use std::path::PathBuf;
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("shitcoin-{}-{}.pem", std::process::id(), name))
+  }
+
+  #[test]
+  fn test_private_key_round_trip() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message("hello", &private_key);
+    let path = temp_path("private");
+    save_private_key(&private_key, &path).unwrap();
+    let loaded = load_private_key(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let loaded_public_key = RsaPublicKey::from(&loaded);
+    assert_eq!(loaded_public_key, public_key);
+    assert!(verify_message("hello", &signature, &loaded_public_key));
+    let (_private_key2, public_key2) = generate_key_pair().unwrap();
+    let transaction = Transaction::new(loaded_public_key, loaded, public_key2, 10 * COIN, 1);
+    assert!(transaction.verify());
+  }
+
+  #[test]
+  fn test_public_key_round_trip() {
+    let (private_key, public_key) = generate_key_pair().unwrap();
+    let signature = sign_message("hello", &private_key);
+    let path = temp_path("public");
+    save_public_key(&public_key, &path).unwrap();
+    let loaded = load_public_key(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(verify_message("hello", &signature, &loaded));
+  }
+
+  #[test]
+  fn test_wallet() {
+    let wallet = Wallet::new().unwrap();
+    let other = Wallet::new().unwrap();
+    let transaction = wallet.create_transaction_with_uid(other.public_key(), 10 * COIN, 1, COIN);
+    assert!(transaction.verify());
+    assert_eq!(transaction.sender(), wallet.public_key());
+    assert_eq!(transaction.receiver(), other.public_key());
+    assert_eq!(transaction.fee(), COIN);
+    assert_eq!(Address::from_public_key(transaction.sender()), wallet.address());
+
+    let mut chain = Blockchain::with_difficulty(8);
+    assert_eq!(wallet.balance(&chain), 0);
+    chain.add_block(Vec::new(), &wallet.private_key).unwrap();
+    assert_eq!(wallet.balance(&chain), blockchain::BLOCK_REWARD);
+
+    let path = temp_path("wallet");
+    wallet.save(&path).unwrap();
+    let loaded = Wallet::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.address(), wallet.address());
+  }
+
+  #[test]
+  fn test_wallet_assigns_increasing_uids() {
+    let mut wallet = Wallet::new().unwrap();
+    let other = Wallet::new().unwrap();
+    let first = wallet.create_transaction(other.public_key(), COIN, 0);
+    let second = wallet.create_transaction(other.public_key(), COIN, 0);
+    assert!(second.uid() > first.uid());
+    assert!(first.verify() && second.verify());
+    assert!(first.uid_encodes_sender() && second.uid_encodes_sender());
+
+    // both can go in the same block
+    let mut chain = Blockchain::with_difficulty(8);
+    chain.add_block(Vec::new(), &wallet.private_key).unwrap();
+    chain.add_block(vec![first, second], &other.private_key).unwrap();
+    assert_eq!(other.balance(&chain), blockchain::BLOCK_REWARD + 2 * COIN);
+  }
+
+  #[test]
+  fn test_load_missing_key() {
+    let result = load_private_key(&temp_path("missing"));
+    assert!(matches!(result, Err(KeyError::Io(_))));
+  }
+}